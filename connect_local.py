@@ -6,6 +6,8 @@ import json
 import matplotlib.pyplot as plt
 
 
+# Generate the keys of the replicas once before running them locally:
+# ./target/debug/fast_internet_computer_consensus --generate_subnet_keys --r 1 --n 2 --f 0
 # Example command to run locally:
 # ./target/debug/fast_internet_computer_consensus --cod --r 2 --n 2 --f 0 --p 0 --t 20 --d 3000 --broadcast_interval 10 --artifact_manager_polling_interval 20 --broadcast_interval_ramp_up 100 --ramp_up_time 100 --port 56791 --blocksize 5
 
@@ -1,15 +1,23 @@
 use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use futures::channel::mpsc::UnboundedSender;
 use std::thread::Builder as ThreadBuilder;
 use std::{
     collections::BTreeMap,
     sync::{Arc, Mutex, RwLock},
 };
 
+use crate::crypto::CryptoProvider;
 use crate::HeightMetrics;
 use crate::{
     consensus_layer::{
-        artifacts::{ConsensusMessage, UnvalidatedArtifact},
+        artifacts::{
+            ConsensusMessage, ConsensusMessageId, InvalidArtifactCounts, UnvalidatedArtifact,
+        },
+        consensus_subcomponents::validator::EquivocationEvidence,
         height_index::Height,
+        payload_store::PayloadStore,
+        pool::ConsensusPoolImpl,
+        pool_reader::PoolReader,
         ConsensusProcessor,
     },
     SubnetParams,
@@ -33,22 +41,39 @@ pub struct ArtifactProcessorManager {
     pending_artifacts: Arc<Mutex<Vec<UnvalidatedArtifact<ConsensusMessage>>>>,
     // To send the process requests
     sender_incoming_request: Sender<ProcessRequest>,
+    // The consensus pool, read to answer the requests of the peers
+    consensus_pool: Arc<RwLock<ConsensusPoolImpl>>,
     // Handle for the processing thread
     //handle: Option<JoinHandle<()>>,
 }
 
 impl ArtifactProcessorManager {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         replica_number: u8,
         subnet_params: SubnetParams,
-        sender_outgoing_artifact: Sender<ConsensusMessage>,
+        crypto: Arc<dyn CryptoProvider>,
+        sender_outgoing_artifact: UnboundedSender<ConsensusMessage>,
         finalization_times: Arc<RwLock<BTreeMap<Height, Option<HeightMetrics>>>>,
+        invalid_artifacts: Arc<RwLock<InvalidArtifactCounts>>,
+        equivocation_evidence: Arc<RwLock<Vec<EquivocationEvidence>>>,
+        sender_artifact_request: Sender<ConsensusMessageId>,
+        payloads: Arc<RwLock<PayloadStore>>,
     ) -> Self {
         let pending_artifacts = Arc::new(Mutex::new(Vec::new()));
         let (sender_incoming_request, receiver_incoming_request) =
             crossbeam_channel::unbounded::<ProcessRequest>();
 
-        let client = ConsensusProcessor::new(replica_number, subnet_params.clone());
+        let client = ConsensusProcessor::new(
+            replica_number,
+            subnet_params.clone(),
+            crypto,
+            invalid_artifacts,
+            equivocation_evidence,
+            sender_artifact_request,
+            payloads,
+        );
+        let consensus_pool = client.consensus_pool();
 
         // Spawn the processor thread
         let sender_incoming_request_cl = sender_incoming_request.clone();
@@ -71,6 +96,7 @@ impl ArtifactProcessorManager {
         Self {
             pending_artifacts,
             sender_incoming_request,
+            consensus_pool,
             //handle: Some(handle),
         }
     }
@@ -80,7 +106,7 @@ impl ArtifactProcessorManager {
         client: ConsensusProcessor,
         sender_incoming_request: Sender<ProcessRequest>,
         receiver_incoming_request: Receiver<ProcessRequest>,
-        sender_outgoing_artifact: Sender<ConsensusMessage>,
+        sender_outgoing_artifact: UnboundedSender<ConsensusMessage>,
         finalization_times: Arc<RwLock<BTreeMap<Height, Option<HeightMetrics>>>>,
         subnet_params: SubnetParams,
     ) {
@@ -110,7 +136,7 @@ impl ArtifactProcessorManager {
                     adverts.into_iter().for_each(|adv| {
                         // use channel to send locally generated artifacts to network layer so that it can broadcast them
                         sender_outgoing_artifact
-                            .send(adv)
+                            .unbounded_send(adv)
                             .unwrap_or_else(|err| panic!("Failed to send artifact: {:?}", err));
                     });
                 }
@@ -126,4 +152,9 @@ impl ArtifactProcessorManager {
             .send(ProcessRequest)
             .unwrap_or_else(|err| panic!("Failed to send request: {:?}", err));
     }
+
+    pub fn on_artifact_request(&self, request: &ConsensusMessageId) -> Vec<ConsensusMessage> {
+        let consensus_pool = self.consensus_pool.read().unwrap();
+        PoolReader::new(&consensus_pool).get_requested_artifacts(request)
+    }
 }
@@ -1,14 +1,23 @@
 use crate::artifact_manager::ProcessingResult;
-use crate::{HeightMetrics, SubnetParams};
+use crate::{crypto::CryptoProvider, HeightMetrics, SubnetParams};
 
 pub mod pool;
-use crate::consensus_layer::pool::ConsensusPoolImpl;
+use crate::consensus_layer::pool::{ConsensusPoolImpl, InMemoryPoolSection, MutablePoolSection};
+
+pub mod persistent_pool;
+use crate::consensus_layer::persistent_pool::PersistentPoolSection;
+
+pub mod payload_store;
+use crate::consensus_layer::payload_store::PayloadStore;
 
 pub mod consensus;
 use crate::consensus_layer::consensus::ConsensusImpl;
 
 pub mod artifacts;
-use crate::consensus_layer::artifacts::{ChangeAction, ConsensusMessage, UnvalidatedArtifact};
+use crate::consensus_layer::artifacts::{
+    ChangeAction, ConsensusMessage, ConsensusMessageId, InvalidArtifactCounts, UnvalidatedArtifact,
+};
+use crate::consensus_layer::consensus_subcomponents::validator::EquivocationEvidence;
 
 pub mod pool_reader;
 
@@ -19,6 +28,8 @@ pub mod consensus_subcomponents;
 use std::collections::BTreeMap;
 use std::sync::{Arc, RwLock};
 
+use crossbeam_channel::Sender;
+
 use self::height_index::Height;
 
 pub struct ConsensusProcessor {
@@ -27,13 +38,46 @@ pub struct ConsensusProcessor {
 }
 
 impl ConsensusProcessor {
-    pub fn new(replica_number: u8, subnet_params: SubnetParams) -> Self {
+    pub fn new(
+        replica_number: u8,
+        subnet_params: SubnetParams,
+        crypto: Arc<dyn CryptoProvider>,
+        invalid_artifacts: Arc<RwLock<InvalidArtifactCounts>>,
+        equivocation_evidence: Arc<RwLock<Vec<EquivocationEvidence>>>,
+        sender_artifact_request: Sender<ConsensusMessageId>,
+        payloads: Arc<RwLock<PayloadStore>>,
+    ) -> Self {
+        let validated: Box<dyn MutablePoolSection<_> + Send + Sync> = match &subnet_params.pool_dir
+        {
+            Some(pool_dir) => {
+                std::fs::create_dir_all(pool_dir).expect("can create pool directory");
+                let path = pool_dir.join(format!("replica_{}_validated.log", replica_number));
+                println!("Persisting validated artifacts in {:?}", path);
+                Box::new(PersistentPoolSection::open(&path))
+            }
+            None => Box::new(InMemoryPoolSection::new()),
+        };
         Self {
-            consensus_pool: Arc::new(RwLock::new(ConsensusPoolImpl::new())),
-            client: Box::new(ConsensusImpl::new(replica_number, subnet_params)),
+            consensus_pool: Arc::new(RwLock::new(ConsensusPoolImpl::new(
+                validated,
+                invalid_artifacts,
+                equivocation_evidence,
+            ))),
+            client: Box::new(ConsensusImpl::new(
+                replica_number,
+                subnet_params,
+                crypto,
+                sender_artifact_request,
+                payloads,
+            )),
         }
     }
 
+    /// Get the consensus pool, from which the requests of the peers are answered.
+    pub fn consensus_pool(&self) -> Arc<RwLock<ConsensusPoolImpl>> {
+        Arc::clone(&self.consensus_pool)
+    }
+
     pub fn process_changes(
         &self,
         artifacts: Vec<UnvalidatedArtifact<ConsensusMessage>>,
@@ -60,6 +104,11 @@ impl ConsensusProcessor {
         if to_broadcast {
             for change_action in change_set.iter() {
                 match change_action {
+                    // goodness and "I made a block" artifacts are local bookkeeping, which peers reject
+                    ChangeAction::AddToValidated(
+                        ConsensusMessage::GoodnessArtifact(_)
+                        | ConsensusMessage::IMadeABlockArtifact(_),
+                    ) => {}
                     ChangeAction::AddToValidated(to_add) => {
                         // println!("Broadcasting consensus message to be added: {:?}", to_add);
                         adverts.push(to_add.to_owned());
@@ -68,6 +117,10 @@ impl ConsensusProcessor {
                         // println!("Broadcasting consensus message to be moved: {:?}", to_move);
                         adverts.push(to_move.to_owned());
                     }
+                    // removals from the unvalidated section are local to each replica
+                    ChangeAction::RemoveFromValidated(_)
+                    | ChangeAction::RemoveFromUnvalidated(_)
+                    | ChangeAction::HandleInvalid(_, _) => {}
                 }
             }
         }
@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
 use crate::{
@@ -8,9 +10,12 @@ use crate::{
 use super::consensus_subcomponents::{
     aggregator::{Finalization, Notarization},
     block_maker::BlockProposal,
+    catch_up_package_maker::{CatchUpPackage, CatchUpPackageShare},
     finalizer::FinalizationShare,
     goodifier::{GoodnessArtifact, IMadeABlockArtifact},
     notary::{NotarizationShare, NotarizationShareContent},
+    random_beacon_maker::{RandomBeacon, RandomBeaconShare},
+    validator::EquivocationEvidence,
 };
 
 pub type ChangeSet = Vec<ChangeAction>;
@@ -19,8 +24,28 @@ pub type ChangeSet = Vec<ChangeAction>;
 pub enum ChangeAction {
     AddToValidated(ConsensusMessage),
     MoveToValidated(ConsensusMessage),
+    RemoveFromValidated(ConsensusMessage),
+    RemoveFromUnvalidated(ConsensusMessage),
+    HandleInvalid(ConsensusMessage, InvalidArtifactReason),
+}
+
+/// Reason for which an unvalidated artifact is rejected by the validator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum InvalidArtifactReason {
+    InvalidSignature,
+    HashMismatch,
+    ParentMismatch,
+    HeightMismatch,
+    RankMismatch,
+    DuplicateShare,
+    InvalidEvidence,
+    // goodness and "I made a block" artifacts are only produced locally and never broadcast
+    LocalArtifact,
 }
 
+/// Number of rejected artifacts by peer they were received from and by reason
+pub type InvalidArtifactCounts = BTreeMap<u8, BTreeMap<InvalidArtifactReason, u64>>;
+
 impl From<ChangeAction> for ChangeSet {
     fn from(action: ChangeAction) -> Self {
         vec![action]
@@ -42,19 +67,25 @@ pub trait HasTimestamp {
     fn timestamp(&self) -> Time;
 }
 
+/// Sender of the artifacts received from a peer which cannot be authenticated,
+/// e.g. when floodsub forwards a message on behalf of its claimed author. Such
+/// artifacts are attributed to the replica that signed them instead.
+pub const UNKNOWN_SENDER: u8 = 0;
+
 // Unvalidated artifact
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct UnvalidatedArtifact<T> {
     pub message: T,
+    // replica number of the peer from which the artifact was received, or UNKNOWN_SENDER
     pub peer_id: u8,
     pub timestamp: Time,
 }
 
 impl<T> UnvalidatedArtifact<T> {
-    pub fn new(artifact: T, timestamp: Time) -> Self {
+    pub fn new(artifact: T, peer_id: u8, timestamp: Time) -> Self {
         Self {
             message: artifact,
-            peer_id: 0,
+            peer_id,
             timestamp,
         }
     }
@@ -99,6 +130,8 @@ impl<T> HasTimestamp for ValidatedArtifact<T> {
 
 pub trait ConsensusMessageHashable: Clone {
     fn get_id(&self) -> ConsensusMessageId;
+    // the hash covers the message type, as the pool identifies artifacts of all types by their hash
+    // and artifacts of different types may be serialized alike
     fn get_cm_hash(&self) -> ConsensusMessageHash;
     fn assert(msg: &ConsensusMessage) -> Option<&Self>;
 }
@@ -111,14 +144,29 @@ pub enum ConsensusMessage {
     FinalizationShare(FinalizationShare),
     Finalization(Finalization),
     GoodnessArtifact(GoodnessArtifact), // does not require to be signed as it is never broadcasted
-    IMadeABlockArtifact(IMadeABlockArtifact),
+    IMadeABlockArtifact(IMadeABlockArtifact), // neither, as it is only used to measure the local latency
+    RandomBeaconShare(RandomBeaconShare),
+    RandomBeacon(RandomBeacon),
+    EquivocationEvidence(EquivocationEvidence),
+    CatchUpPackageShare(CatchUpPackageShare),
+    CatchUpPackage(CatchUpPackage),
 }
 
 impl ConsensusMessageHashable for ConsensusMessage {
     fn get_id(&self) -> ConsensusMessageId {
-        ConsensusMessageId {
-            hash: self.get_cm_hash(),
-            height: 1,
+        match self {
+            ConsensusMessage::BlockProposal(value) => value.get_id(),
+            ConsensusMessage::NotarizationShare(value) => value.get_id(),
+            ConsensusMessage::Notarization(value) => value.get_id(),
+            ConsensusMessage::FinalizationShare(value) => value.get_id(),
+            ConsensusMessage::Finalization(value) => value.get_id(),
+            ConsensusMessage::GoodnessArtifact(value) => value.get_id(),
+            ConsensusMessage::IMadeABlockArtifact(value) => value.get_id(),
+            ConsensusMessage::RandomBeaconShare(value) => value.get_id(),
+            ConsensusMessage::RandomBeacon(value) => value.get_id(),
+            ConsensusMessage::EquivocationEvidence(value) => value.get_id(),
+            ConsensusMessage::CatchUpPackageShare(value) => value.get_id(),
+            ConsensusMessage::CatchUpPackage(value) => value.get_id(),
         }
     }
 
@@ -131,6 +179,11 @@ impl ConsensusMessageHashable for ConsensusMessage {
             ConsensusMessage::Finalization(value) => value.get_cm_hash(),
             ConsensusMessage::GoodnessArtifact(value) => value.get_cm_hash(),
             ConsensusMessage::IMadeABlockArtifact(value) => value.get_cm_hash(),
+            ConsensusMessage::RandomBeaconShare(value) => value.get_cm_hash(),
+            ConsensusMessage::RandomBeacon(value) => value.get_cm_hash(),
+            ConsensusMessage::EquivocationEvidence(value) => value.get_cm_hash(),
+            ConsensusMessage::CatchUpPackageShare(value) => value.get_cm_hash(),
+            ConsensusMessage::CatchUpPackage(value) => value.get_cm_hash(),
         }
     }
 
@@ -139,6 +192,27 @@ impl ConsensusMessageHashable for ConsensusMessage {
     }
 }
 
+impl ConsensusMessage {
+    /// Return the replica that signed the artifact, unless it is signed by
+    /// several replicas or not signed at all.
+    pub fn signer(&self) -> Option<u8> {
+        match self {
+            ConsensusMessage::BlockProposal(value) => Some(value.signature.signer),
+            ConsensusMessage::NotarizationShare(value) => Some(value.signature.signer),
+            ConsensusMessage::FinalizationShare(value) => Some(value.signature.signer),
+            ConsensusMessage::RandomBeaconShare(value) => Some(value.signature.signer),
+            ConsensusMessage::CatchUpPackageShare(value) => Some(value.signature.signer),
+            ConsensusMessage::Notarization(_)
+            | ConsensusMessage::Finalization(_)
+            | ConsensusMessage::RandomBeacon(_)
+            | ConsensusMessage::CatchUpPackage(_)
+            | ConsensusMessage::EquivocationEvidence(_)
+            | ConsensusMessage::GoodnessArtifact(_)
+            | ConsensusMessage::IMadeABlockArtifact(_) => None,
+        }
+    }
+}
+
 /// Consensus message identifier carries both a message hash and a height,
 /// which is used by the consensus pool to help lookup.
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -156,7 +230,7 @@ impl ConsensusMessageHashable for BlockProposal {
     }
 
     fn get_cm_hash(&self) -> ConsensusMessageHash {
-        ConsensusMessageHash::BlockProposal(Hashed::crypto_hash(self))
+        ConsensusMessageHash::BlockProposal(Hashed::crypto_hash(&("BlockProposal", self)))
     }
 
     fn assert(msg: &ConsensusMessage) -> Option<&Self> {
@@ -183,7 +257,7 @@ impl ConsensusMessageHashable for NotarizationShare {
     }
 
     fn get_cm_hash(&self) -> ConsensusMessageHash {
-        ConsensusMessageHash::NotarizationShare(Hashed::crypto_hash(self))
+        ConsensusMessageHash::NotarizationShare(Hashed::crypto_hash(&("NotarizationShare", self)))
     }
 
     fn assert(msg: &ConsensusMessage) -> Option<&Self> {
@@ -204,7 +278,7 @@ impl ConsensusMessageHashable for Notarization {
     }
 
     fn get_cm_hash(&self) -> ConsensusMessageHash {
-        ConsensusMessageHash::Notarization(Hashed::crypto_hash(self))
+        ConsensusMessageHash::Notarization(Hashed::crypto_hash(&("Notarization", self)))
     }
 
     fn assert(msg: &ConsensusMessage) -> Option<&Self> {
@@ -225,7 +299,7 @@ impl ConsensusMessageHashable for FinalizationShare {
     }
 
     fn get_cm_hash(&self) -> ConsensusMessageHash {
-        ConsensusMessageHash::FinalizationShare(Hashed::crypto_hash(self))
+        ConsensusMessageHash::FinalizationShare(Hashed::crypto_hash(&("FinalizationShare", self)))
     }
 
     fn assert(msg: &ConsensusMessage) -> Option<&Self> {
@@ -246,7 +320,7 @@ impl ConsensusMessageHashable for Finalization {
     }
 
     fn get_cm_hash(&self) -> ConsensusMessageHash {
-        ConsensusMessageHash::Finalization(Hashed::crypto_hash(self))
+        ConsensusMessageHash::Finalization(Hashed::crypto_hash(&("Finalization", self)))
     }
 
     fn assert(msg: &ConsensusMessage) -> Option<&Self> {
@@ -267,7 +341,7 @@ impl ConsensusMessageHashable for GoodnessArtifact {
     }
 
     fn get_cm_hash(&self) -> ConsensusMessageHash {
-        ConsensusMessageHash::GoodnessArtifact(Hashed::crypto_hash(self))
+        ConsensusMessageHash::GoodnessArtifact(Hashed::crypto_hash(&("GoodnessArtifact", self)))
     }
 
     fn assert(msg: &ConsensusMessage) -> Option<&Self> {
@@ -288,7 +362,10 @@ impl ConsensusMessageHashable for IMadeABlockArtifact {
     }
 
     fn get_cm_hash(&self) -> ConsensusMessageHash {
-        ConsensusMessageHash::IMadeABlockArtifact(Hashed::crypto_hash(self))
+        ConsensusMessageHash::IMadeABlockArtifact(Hashed::crypto_hash(&(
+            "IMadeABlockArtifact",
+            self,
+        )))
     }
 
     fn assert(msg: &ConsensusMessage) -> Option<&Self> {
@@ -299,3 +376,114 @@ impl ConsensusMessageHashable for IMadeABlockArtifact {
         }
     }
 }
+
+impl ConsensusMessageHashable for RandomBeaconShare {
+    fn get_id(&self) -> ConsensusMessageId {
+        ConsensusMessageId {
+            hash: self.get_cm_hash(),
+            height: self.content.height,
+        }
+    }
+
+    fn get_cm_hash(&self) -> ConsensusMessageHash {
+        ConsensusMessageHash::RandomBeaconShare(Hashed::crypto_hash(&("RandomBeaconShare", self)))
+    }
+
+    fn assert(msg: &ConsensusMessage) -> Option<&Self> {
+        if let ConsensusMessage::RandomBeaconShare(value) = msg {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+impl ConsensusMessageHashable for RandomBeacon {
+    fn get_id(&self) -> ConsensusMessageId {
+        ConsensusMessageId {
+            hash: self.get_cm_hash(),
+            height: self.content.height,
+        }
+    }
+
+    fn get_cm_hash(&self) -> ConsensusMessageHash {
+        ConsensusMessageHash::RandomBeacon(Hashed::crypto_hash(&("RandomBeacon", self)))
+    }
+
+    fn assert(msg: &ConsensusMessage) -> Option<&Self> {
+        if let ConsensusMessage::RandomBeacon(value) = msg {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+impl ConsensusMessageHashable for EquivocationEvidence {
+    fn get_id(&self) -> ConsensusMessageId {
+        ConsensusMessageId {
+            hash: self.get_cm_hash(),
+            height: self.height(),
+        }
+    }
+
+    fn get_cm_hash(&self) -> ConsensusMessageHash {
+        ConsensusMessageHash::EquivocationEvidence(Hashed::crypto_hash(&(
+            "EquivocationEvidence",
+            self,
+        )))
+    }
+
+    fn assert(msg: &ConsensusMessage) -> Option<&Self> {
+        if let ConsensusMessage::EquivocationEvidence(value) = msg {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+impl ConsensusMessageHashable for CatchUpPackageShare {
+    fn get_id(&self) -> ConsensusMessageId {
+        ConsensusMessageId {
+            hash: self.get_cm_hash(),
+            height: self.content.height(),
+        }
+    }
+
+    fn get_cm_hash(&self) -> ConsensusMessageHash {
+        ConsensusMessageHash::CatchUpPackageShare(Hashed::crypto_hash(&(
+            "CatchUpPackageShare",
+            self,
+        )))
+    }
+
+    fn assert(msg: &ConsensusMessage) -> Option<&Self> {
+        if let ConsensusMessage::CatchUpPackageShare(value) = msg {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+impl ConsensusMessageHashable for CatchUpPackage {
+    fn get_id(&self) -> ConsensusMessageId {
+        ConsensusMessageId {
+            hash: self.get_cm_hash(),
+            height: self.content.height(),
+        }
+    }
+
+    fn get_cm_hash(&self) -> ConsensusMessageHash {
+        ConsensusMessageHash::CatchUpPackage(Hashed::crypto_hash(&("CatchUpPackage", self)))
+    }
+
+    fn assert(msg: &ConsensusMessage) -> Option<&Self> {
+        if let ConsensusMessage::CatchUpPackage(value) = msg {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
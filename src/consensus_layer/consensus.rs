@@ -3,15 +3,20 @@ use std::{
     sync::{Arc, RwLock},
 };
 
-use crate::{HeightMetrics, SubnetParams};
+use crossbeam_channel::Sender;
+
+use crate::{crypto::CryptoProvider, HeightMetrics, SubnetParams};
 
 use super::{
-    artifacts::{ChangeAction, ChangeSet, ConsensusMessage},
+    artifacts::{ChangeAction, ChangeSet, ConsensusMessage, ConsensusMessageId},
     consensus_subcomponents::{
         acknowledger::Acknowledger, aggregator::ShareAggregator, block_maker::BlockMaker,
-        finalizer::Finalizer, goodifier::Goodifier, notary::Notary, validator::Validator,
+        catch_up_package_maker::CatchUpPackageMaker, finalizer::Finalizer, goodifier::Goodifier,
+        notary::Notary, purger::Purger, random_beacon_maker::RandomBeaconMaker,
+        validator::Validator,
     },
     height_index::Height,
+    payload_store::PayloadStore,
     pool::ConsensusPoolImpl,
     pool_reader::PoolReader,
 };
@@ -49,23 +54,58 @@ pub struct ConsensusImpl {
     acknowledger: Acknowledger,
     finalizer: Finalizer,
     block_maker: BlockMaker,
+    random_beacon_maker: RandomBeaconMaker,
     notary: Notary,
     aggregator: ShareAggregator,
     validator: Validator,
+    purger: Purger,
+    catch_up_package_maker: CatchUpPackageMaker,
     schedule: RoundRobin,
     subnet_params: SubnetParams,
 }
 
 impl ConsensusImpl {
-    pub fn new(replica_number: u8, subnet_params: SubnetParams) -> Self {
+    pub fn new(
+        replica_number: u8,
+        subnet_params: SubnetParams,
+        crypto: Arc<dyn CryptoProvider>,
+        sender_artifact_request: Sender<ConsensusMessageId>,
+        payloads: Arc<RwLock<PayloadStore>>,
+    ) -> Self {
         Self {
             goodifier: Goodifier::new(replica_number, subnet_params.clone()),
-            acknowledger: Acknowledger::new(replica_number, subnet_params.clone()),
-            finalizer: Finalizer::new(replica_number, subnet_params.clone()),
-            block_maker: BlockMaker::new(replica_number, subnet_params.clone()),
-            notary: Notary::new(replica_number, subnet_params.clone()),
-            aggregator: ShareAggregator::new(replica_number, subnet_params.clone()),
-            validator: Validator::new(replica_number),
+            acknowledger: Acknowledger::new(
+                replica_number,
+                subnet_params.clone(),
+                Arc::clone(&crypto),
+            ),
+            finalizer: Finalizer::new(replica_number, subnet_params.clone(), Arc::clone(&crypto)),
+            block_maker: BlockMaker::new(
+                replica_number,
+                subnet_params.clone(),
+                Arc::clone(&crypto),
+                Arc::clone(&payloads),
+            ),
+            random_beacon_maker: RandomBeaconMaker::new(replica_number, Arc::clone(&crypto)),
+            notary: Notary::new(replica_number, subnet_params.clone(), Arc::clone(&crypto)),
+            aggregator: ShareAggregator::new(
+                replica_number,
+                subnet_params.clone(),
+                Arc::clone(&crypto),
+            ),
+            validator: Validator::new(
+                replica_number,
+                subnet_params.clone(),
+                Arc::clone(&crypto),
+                sender_artifact_request,
+                Arc::clone(&payloads),
+            ),
+            purger: Purger::new(replica_number, subnet_params.clone(), payloads),
+            catch_up_package_maker: CatchUpPackageMaker::new(
+                replica_number,
+                subnet_params.clone(),
+                crypto,
+            ),
             schedule: RoundRobin::default(),
             subnet_params,
         }
@@ -103,6 +143,21 @@ impl ConsensusImpl {
                 .on_state_change(&pool_reader, Arc::clone(&finalization_times))
         };
 
+        let purge = || {
+            let change_set = self.purger.on_state_change(&pool_reader);
+            // purging is local to each replica
+            let to_broadcast = false;
+            (change_set, to_broadcast)
+        };
+
+        let detect_equivocation = || {
+            let change_set =
+                add_all_to_validated(self.validator.detect_equivocations(&pool_reader));
+            // evidence is broadcasted so that all replicas learn about the misbehaviour
+            let to_broadcast = true;
+            (change_set, to_broadcast)
+        };
+
         let acknowledge = || {
             if self.subnet_params.fast_internet_computer_consensus {
                 let change_set = add_all_to_validated(
@@ -134,6 +189,13 @@ impl ConsensusImpl {
             (change_set, to_broadcast)
         };
 
+        let make_beacon = || {
+            let change_set =
+                add_all_to_validated(self.random_beacon_maker.on_state_change(&pool_reader));
+            let to_broadcast = true;
+            (change_set, to_broadcast)
+        };
+
         let aggregate = || {
             let change_set = add_all_to_validated(
                 self.aggregator
@@ -144,6 +206,23 @@ impl ConsensusImpl {
             (change_set, to_broadcast)
         };
 
+        let make_catch_up_package = || {
+            let change_set =
+                add_all_to_validated(self.catch_up_package_maker.on_state_change(&pool_reader));
+            let to_broadcast = true;
+            (change_set, to_broadcast)
+        };
+
+        let aggregate_catch_up_package = || {
+            let change_set = add_all_to_validated(
+                self.aggregator
+                    .aggregate_catch_up_package_shares(&pool_reader),
+            );
+            // unlike other aggregated artifacts, catch-up packages are broadcasted so that replicas which lag behind can use them
+            let to_broadcast = true;
+            (change_set, to_broadcast)
+        };
+
         // must be the last component called as it can return the same artifact in multiple iterations
         // running it before the other components might starve them as we break out of the loop
         // as soon as a component returns an artifact
@@ -157,12 +236,17 @@ impl ConsensusImpl {
             }
         };
 
-        let calls: [&'_ dyn Fn() -> (ChangeSet, bool); 7] = [
+        let calls: [&'_ dyn Fn() -> (ChangeSet, bool); 12] = [
             &acknowledge,
             &finalize,
             &aggregate,
             &notarize,
+            &make_beacon,
             &make_block,
+            &make_catch_up_package,
+            &aggregate_catch_up_package,
+            &purge,
+            &detect_equivocation,
             &validate,
             &goodify,
         ];
@@ -4,4 +4,7 @@ pub mod finalizer;
 pub mod validator;
 pub mod aggregator;
 pub mod acknowledger;
-pub mod goodifier;
\ No newline at end of file
+pub mod goodifier;
+pub mod random_beacon_maker;
+pub mod purger;
+pub mod catch_up_package_maker;
\ No newline at end of file
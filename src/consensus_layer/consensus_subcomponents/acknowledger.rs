@@ -1,122 +1,127 @@
+//! The acknowledger is responsible for the fast path of FICC: it FP-finalizes
+//! a block as soon as at least n-p replicas acknowledged it, without waiting
+//! for the finalization shares of the slow path.
+
 use std::{
     collections::BTreeMap,
     sync::{Arc, RwLock},
 };
 
-use super::finalizer::FinalizationShareContent; //, notary::NotarizationShareContentCOD};
+use super::{
+    aggregator::{Finalization, FinalizationContent, Notarization},
+    finalizer::FinalizationShareContent,
+    notary::{NotarizationShareContent, NotarizationShareContentCOD},
+};
 use crate::{
     consensus_layer::{artifacts::ConsensusMessage, height_index::Height, pool_reader::PoolReader},
-    crypto::Signed,
-    HeightMetrics, SubnetParams,
+    crypto::{BasicSignature, CryptoProvider, Signed},
+    FinalizationType, HeightMetrics, SubnetParams,
 };
 
 /// A finalization share is a multi-signature share on a finalization content.
 /// If sufficiently many replicas create finalization shares, the shares can be
 /// aggregated into a full finalization.
-pub type FinalizationShare = Signed<FinalizationShareContent, u8>;
+pub type FinalizationShare = Signed<FinalizationShareContent, BasicSignature>;
 
 pub struct Acknowledger {
-    _node_id: u8,
-    _subnet_params: SubnetParams,
+    node_id: u8,
+    subnet_params: SubnetParams,
+    crypto: Arc<dyn CryptoProvider>,
 }
 
 impl Acknowledger {
-    #[allow(clippy::too_many_arguments)]
-    pub fn new(_node_id: u8, _subnet_params: SubnetParams) -> Self {
+    pub fn new(node_id: u8, subnet_params: SubnetParams, crypto: Arc<dyn CryptoProvider>) -> Self {
         Self {
-            _node_id,
-            _subnet_params,
+            node_id,
+            subnet_params,
+            crypto,
         }
     }
 
     pub fn on_state_change(
         &self,
-        _pool: &PoolReader<'_>,
-        _finalization_times: Arc<RwLock<BTreeMap<Height, Option<HeightMetrics>>>>,
+        pool: &PoolReader<'_>,
+        finalization_times: Arc<RwLock<BTreeMap<Height, Option<HeightMetrics>>>>,
     ) -> Vec<ConsensusMessage> {
-        vec![] /*
-               // println!("\n########## Acknowledger ##########");
-               let finalized_height = pool.get_finalized_height();
-               let notarized_height = pool.get_notarized_height();
-               // heights before the last finalized block do not need to be checked
-               // check heights in which it is still possible for a block to be FP-finalized
-               // even if it was already notarized (happens if F > P)
-               for height in finalized_height + 1..=notarized_height + 1 {
-                   let notarization_shares = pool.get_notarization_shares(height);
-                   let grouped_shares = aggregate(notarization_shares);
-                   let fp_pair_at_height: Vec<ConsensusMessage> = grouped_shares
-                       .into_iter()
-                       .filter_map(|(notarization_content, committee)| {
-                           if let NotarizationShareContent::COD(notarization_content) = notarization_content {
-                               // CoD rule 2: acknowledge (FP-finalize) only blocks whose parent is finalized
-                               // NEW RULES: FP-finalise always!
-                               if notarization_content.is_ack
-                                   && committee.len()
-                                       >= (self.subnet_params.total_nodes_number
-                                           - self.subnet_params.disagreeing_nodes_number)
-                                           as usize
-                                   // && is_parent_finalized(pool, &notarization_content)
-                               {
-                                   println!("\nAcknowledgement of block with hash: {} at height {} by committee: {:?}", notarization_content.block.get_ref(), notarization_content.height, committee);
-                                   if let Some(finalization_time) =
-                                       pool.get_finalization_time(notarization_content.height, self.node_id)
-                                   {
-                                       let height_metrics = HeightMetrics {
-                                           latency: finalization_time,
-                                           fp_finalization: FinalizationType::FP,
-                                       };
-                                       finalization_times
-                                           .write()
-                                           .unwrap()
-                                           .insert(notarization_content.height, Some(height_metrics));
-                                   }
-                                   Some(notarization_content)
-                               } else {
-                                   None
-                               }
-                               .map(|notarization_content| {
-                                   // if a block is acknowledged (>= n-f acks) it must be the only G child
-                                   // therefore, we can send the notarization even before checking whether it is G or not
-                                   // as we know it will be as soon as the 'goodifier' component is run
-                                   vec![
-                                       ConsensusMessage::Notarization(Notarization {
-                                           content: NotarizationContent {
-                                               height: notarization_content.height,
-                                               block: notarization_content.block.clone(),
-                                           },
+        // println!("\n########## Acknowledger ##########");
+        let finalized_height = pool.get_finalized_height();
+        let notarized_height = pool.get_notarized_height();
+        let acks_threshold = self.subnet_params.fp_finalization_threshold();
+        // heights before the last finalized block do not need to be checked
+        // check heights in which it is still possible for a block to be FP-finalized
+        // even if it was not notarized yet, as acks are also notarization shares
+        for height in finalized_height + 1..=notarized_height + 1 {
+            if finalization_times.read().unwrap().contains_key(&height) {
+                continue;
+            }
+            // group acks by the block they acknowledge, keeping both the notarization and the ack signature
+            // of each signer, as an FP-finalized block might not have been notarized yet
+            let grouped_acks = pool.get_notarization_shares(height).fold(
+                BTreeMap::<
+                    NotarizationShareContentCOD,
+                    BTreeMap<u8, (BasicSignature, BasicSignature)>,
+                >::new(),
+                |mut grouped_acks, share| {
+                    // shares of replicas running the original IC consensus are not acknowledgements
+                    if let (NotarizationShareContent::COD(content), Some(ack)) =
+                        (share.content, share.signature.ack_signature())
+                    {
+                        grouped_acks
+                            .entry(content)
+                            .or_default()
+                            .insert(ack.signer, (share.signature.notarization_signature(), ack));
+                    }
+                    grouped_acks
+                },
+            );
+            for (content, committee) in grouped_acks {
+                if committee.len() < acks_threshold {
+                    continue;
+                }
+                // CoD rule 2: acknowledge (FP-finalize) only blocks whose parent is finalized
+                if self
+                    .subnet_params
+                    .fp_finalization_requires_finalized_parent()
+                    && !is_parent_finalized(pool, content.height, &content.block_parent_hash)
+                {
+                    continue;
+                }
+                // println!("\nAcknowledgement of block with hash: {} at height {} by committee: {:?}", content.block.get_ref(), content.height, committee.keys());
+                let height_metrics = HeightMetrics {
+                    latency: pool.get_finalization_time(height, self.node_id),
+                    fp_finalization: FinalizationType::FP,
+                };
+                finalization_times
+                    .write()
+                    .unwrap()
+                    .insert(height, Some(height_metrics));
 
-                                           signature: 0, // committee signature
-                                       }),
-                                       ConsensusMessage::Finalization(Finalization {
-                                           content: FinalizationContent {
-                                               height: notarization_content.height,
-                                               block: notarization_content.block,
-                                           },
-                                           signature: 50, // committee signature
-                                       }),
-                                   ]
-                               })
-                           } else {
-                               panic!("acknowledger called while running original IC consensus");
-                           }
-                       })
-                       .flatten()
-                       .collect();
-                   if !fp_pair_at_height.is_empty() {
-                       return fp_pair_at_height;
-                   }
-               }
-               vec![]*/
+                let (notarization_signatures, ack_signatures): (Vec<_>, Vec<_>) =
+                    committee.into_values().unzip();
+                let mut fp_pair = vec![];
+                // if a block is acknowledged (>= n-p acks) it must be the only good child,
+                // so its notarization can be sent along with the FP-finalization
+                if pool.get_notarized_block(&content.block, height).is_none() {
+                    fp_pair.push(ConsensusMessage::Notarization(Notarization {
+                        content: NotarizationShareContent::COD(content.clone())
+                            .notarization_content(),
+                        signature: self.crypto.aggregate(notarization_signatures), // committee signature
+                    }));
+                }
+                fp_pair.push(ConsensusMessage::Finalization(Finalization {
+                    content: FinalizationContent::new_fp(content.height, content.block),
+                    signature: self.crypto.aggregate(ack_signatures), // committee signature
+                }));
+                return fp_pair;
+            }
+        }
+        vec![]
     }
 }
 
-/*
-fn is_parent_finalized(
-    pool: &PoolReader<'_>,
-    notarization_content: &NotarizationShareContentCOD,
-) -> bool {
-    let parent_hash = notarization_content.block_parent_hash.clone();
-    let parent_height = notarization_content.height - 1;
+/// Return true if the parent of the block at `height` is the block finalized at the previous height.
+pub fn is_parent_finalized(pool: &PoolReader<'_>, height: Height, parent_hash: &str) -> bool {
+    let parent_height = height - 1;
     if parent_height == 0 {
         return true; // genesis block is finalized
     }
@@ -125,4 +130,3 @@ fn is_parent_finalized(
         None => false,
     }
 }
-*/
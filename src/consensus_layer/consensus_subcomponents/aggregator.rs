@@ -5,14 +5,17 @@
 
 use crate::consensus_layer::height_index::Height;
 use crate::consensus_layer::{artifacts::ConsensusMessage, pool_reader::PoolReader};
-use crate::crypto::{CryptoHashOf, Hashed, Signed, TurboHash};
+use crate::crypto::{
+    to_bytes, BasicSignature, CryptoHashOf, CryptoProvider, Domain, MultiSignature, Signed,
+};
 use crate::{FinalizationType, HeightMetrics, SubnetParams};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::sync::{Arc, RwLock};
 
 use super::block_maker::Block;
-use super::notary::NotarizationShareContent;
+use super::catch_up_package_maker::CatchUpPackage;
+use super::random_beacon_maker::RandomBeacon;
 
 // NotarizationContent holds the values that are signed in a notarization
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
@@ -21,6 +24,10 @@ pub struct NotarizationContent {
     pub block: CryptoHashOf<Block>,
 }
 
+impl Domain for NotarizationContent {
+    const DOMAIN: &'static str = "notarization_content";
+}
+
 impl NotarizationContent {
     pub fn new(block_height: Height, block_hash: CryptoHashOf<Block>) -> Self {
         Self {
@@ -30,48 +37,61 @@ impl NotarizationContent {
     }
 }
 
-impl TurboHash for NotarizationContent {
-    fn tubro_hash(&self) -> String {
-        format!("NCA{}", self.height)
-    }
-}
-
-pub type Notarization = Signed<NotarizationContent, u8>;
+/// A notarization is a multi-signature on a NotarizationContent. A
+/// notarization proves that the block identified by the block hash in the
+/// notarization content was validated by enough replicas.
+pub type Notarization = Signed<NotarizationContent, MultiSignature>;
 
-/// FinalizationContent holds the values that are signed in a finalization
+/// FinalizationContent holds the values that are signed in a finalization.
+/// FP-finalizations are aggregated from acknowledgements, which sign the
+/// content with `fp_finalization` set so that they can never be mistaken for
+/// finalization shares.
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct FinalizationContent {
     pub height: Height,
     pub block: CryptoHashOf<Block>,
+    pub fp_finalization: bool,
+}
+
+impl Domain for FinalizationContent {
+    const DOMAIN: &'static str = "finalization_content";
 }
 
 impl FinalizationContent {
     pub fn new(height: Height, block: CryptoHashOf<Block>) -> Self {
-        FinalizationContent { height, block }
+        FinalizationContent {
+            height,
+            block,
+            fp_finalization: false,
+        }
     }
-}
 
-impl TurboHash for FinalizationContent {
-    fn tubro_hash(&self) -> String {
-        format!("FCA{}", self.height)
+    pub fn new_fp(height: Height, block: CryptoHashOf<Block>) -> Self {
+        FinalizationContent {
+            height,
+            block,
+            fp_finalization: true,
+        }
     }
 }
 
 /// A finalization is a multi-signature on a FinalizationContent. A finalization
 /// proves that the block identified by the block hash in the finalization
 /// content (and the block chain it implies) is agreed upon.
-pub type Finalization = Signed<FinalizationContent, u8>;
+pub type Finalization = Signed<FinalizationContent, MultiSignature>;
 
 pub struct ShareAggregator {
     node_id: u8,
     subnet_params: SubnetParams,
+    crypto: Arc<dyn CryptoProvider>,
 }
 
 impl ShareAggregator {
-    pub fn new(node_id: u8, subnet_params: SubnetParams) -> Self {
+    pub fn new(node_id: u8, subnet_params: SubnetParams, crypto: Arc<dyn CryptoProvider>) -> Self {
         Self {
             node_id,
             subnet_params,
+            crypto,
         }
     }
 
@@ -84,96 +104,97 @@ impl ShareAggregator {
     ) -> Vec<ConsensusMessage> {
         // println!("\n########## Aggregator ##########");
         let mut messages = Vec::new();
-        messages.append(&mut self.aggregate_notarization_shares(pool, finalization_times.clone()));
+        messages.append(&mut self.aggregate_random_beacon_shares(pool));
+        messages.append(&mut self.aggregate_notarization_shares(pool));
         messages.append(&mut self.aggregate_finalization_shares(pool, finalization_times));
         messages
     }
 
+    /// Attempt to construct the next `RandomBeacon` from the shares extending
+    /// the latest random beacon
+    fn aggregate_random_beacon_shares(&self, pool: &PoolReader<'_>) -> Vec<ConsensusMessage> {
+        let beacon = pool.get_random_beacon_tip();
+        let parent = CryptoHashOf::from(self.crypto.hash(&to_bytes(&beacon)));
+        let height = beacon.content.height + 1;
+        let shares: Vec<_> = pool
+            .get_random_beacon_shares(height)
+            .filter(|share| share.content.parent == parent)
+            .collect();
+        let content = match shares.first() {
+            Some(share) => share.content.clone(),
+            None => return vec![],
+        };
+        self.crypto
+            .combine_threshold_shares(shares.into_iter().map(|share| share.signature).collect())
+            .map(|signature| ConsensusMessage::RandomBeacon(RandomBeacon { content, signature }))
+            .into_iter()
+            .collect()
+    }
+
     /// Attempt to construct `Notarization`s at `notarized_height + 1`
-    fn aggregate_notarization_shares(
-        &self,
-        pool: &PoolReader<'_>,
-        finalization_times: Arc<RwLock<BTreeMap<Height, Option<HeightMetrics>>>>,
-    ) -> Vec<ConsensusMessage> {
-        let fin_height = pool.get_finalized_height() + 1;
+    fn aggregate_notarization_shares(&self, pool: &PoolReader<'_>) -> Vec<ConsensusMessage> {
         let mut stuff = vec![];
 
         let mut turbo_height = pool.get_notarized_height() + 1;
-        while {
-            let notarization_shares = pool.get_notarization_shares(turbo_height);
-            if notarization_shares.count()
-                > ((self.subnet_params.total_nodes_number
-                    + self.subnet_params.byzantine_nodes_number)
-                    / 2) as usize
-            {
-                stuff.extend({
-                    let notary_content = pool
-                        .get_notarization_shares(turbo_height)
-                        .next()
-                        .unwrap()
-                        .content;
-                    let notary_content = match notary_content {
-                        NotarizationShareContent::COD(notary_content) => NotarizationContent {
-                            height: notary_content.height,
-                            block: notary_content.block,
-                        },
-                        NotarizationShareContent::ICC(notary_content) => NotarizationContent {
-                            height: notary_content.height,
-                            block: notary_content.block,
-                        },
-                    };
-                    Some(ConsensusMessage::Notarization(Notarization {
-                        content: NotarizationContent {
-                            height: notary_content.height,
-                            block: notary_content.block,
-                        },
-                        signature: 0, // committee signature
-                    }))
-                });
-                turbo_height += 1;
-                true
-            } else {
-                false
-            }
-        } {}
-
-        if self.subnet_params.fast_internet_computer_consensus {
-            for height in fin_height..turbo_height {
-                let shares_num = pool.get_notarization_shares(height).count();
-                if shares_num
-                    >= (self.subnet_params.total_nodes_number
-                        - self.subnet_params.disagreeing_nodes_number)
-                        as usize
-                {
-                    let finalization_content = pool.get_block(height).unwrap();
-                    if !finalization_times.read().unwrap().contains_key(&height) {
-                        let finalization_time = pool.get_finalization_time(height, self.node_id);
-                        let height_metrics = HeightMetrics {
-                            latency: finalization_time,
-                            fp_finalization: FinalizationType::FP,
-                        };
-                        finalization_times
-                            .write()
-                            .unwrap()
-                            .insert(finalization_content.height, Some(height_metrics));
-
-                        stuff.append(&mut vec![ConsensusMessage::Finalization(Finalization {
-                            content: FinalizationContent {
-                                height: finalization_content.height,
-                                block: CryptoHashOf::new(Hashed::crypto_hash(
-                                    &finalization_content,
-                                )),
-                            },
-                            signature: 50, // committee signature
-                        })]);
-                    };
-                }
+        loop {
+            let grouped_shares =
+                aggregate(pool.get_notarization_shares(turbo_height).map(|share| {
+                    (
+                        share.content.notarization_content(),
+                        share.signature.notarization_signature(),
+                    )
+                }));
+            let notarizations: Vec<ConsensusMessage> = grouped_shares
+                .into_iter()
+                .filter(|(_, committee)| committee.len() >= self.subnet_params.quorum())
+                .map(|(content, committee)| {
+                    ConsensusMessage::Notarization(Notarization {
+                        content,
+                        signature: self.crypto.aggregate(committee.into_values().collect()), // committee signature
+                    })
+                })
+                .collect();
+            if notarizations.is_empty() {
+                break;
             }
+            stuff.extend(notarizations);
+            turbo_height += 1;
         }
 
         stuff
     }
 
+    /// Attempt to construct the `CatchUpPackage` of the greatest height for
+    /// which there are catch-up package shares, if it is above the latest
+    /// catch-up package
+    pub fn aggregate_catch_up_package_shares(
+        &self,
+        pool: &PoolReader<'_>,
+    ) -> Vec<ConsensusMessage> {
+        let height = match pool
+            .pool()
+            .validated()
+            .catch_up_package_share()
+            .max_height()
+        {
+            Some(height) if height > pool.get_catch_up_height() => height,
+            _ => return vec![],
+        };
+        aggregate(
+            pool.get_catch_up_package_shares(height)
+                .map(|share| (share.content, share.signature)),
+        )
+        .into_iter()
+        .filter(|(_, committee)| committee.len() >= self.subnet_params.quorum())
+        .map(|(content, committee)| {
+            ConsensusMessage::CatchUpPackage(CatchUpPackage {
+                content,
+                signature: self.crypto.aggregate(committee.into_values().collect()), // committee signature
+            })
+        })
+        .collect()
+    }
+
     /// Attempt to construct `Finalization`s
     fn aggregate_finalization_shares(
         &self,
@@ -182,16 +203,15 @@ impl ShareAggregator {
     ) -> Vec<ConsensusMessage> {
         let mut stuff = vec![];
         for height in pool.get_finalized_height() + 1..=pool.get_notarized_height() {
-            let finlization_shares = pool.get_finalization_shares(height, height);
-            if finlization_shares.count()
-                > ((self.subnet_params.total_nodes_number
-                    + self.subnet_params.byzantine_nodes_number)
-                    / 2) as usize
-            {
-                let finalization_content = pool.get_block(height).unwrap();
-                if !finalization_times.read().unwrap().contains_key(&height) {
-                    let finalization_time =
-                        pool.get_finalization_time(finalization_content.height, self.node_id);
+            let grouped_shares = aggregate(
+                pool.get_finalization_shares(height, height)
+                    .map(|share| (share.content, share.signature)),
+            );
+            for (finalization_content, committee) in grouped_shares {
+                if committee.len() >= self.subnet_params.quorum()
+                    && !finalization_times.read().unwrap().contains_key(&height)
+                {
+                    let finalization_time = pool.get_finalization_time(height, self.node_id);
                     let height_metrics = HeightMetrics {
                         latency: finalization_time,
                         fp_finalization: FinalizationType::IC,
@@ -200,43 +220,39 @@ impl ShareAggregator {
                     finalization_times
                         .write()
                         .unwrap()
-                        .insert(finalization_content.height, Some(height_metrics));
+                        .insert(height, Some(height_metrics));
 
                     stuff.push(ConsensusMessage::Finalization(Finalization {
-                        content: FinalizationContent {
-                            height: finalization_content.height,
-                            block: CryptoHashOf::new(Hashed::crypto_hash(&finalization_content)),
-                        },
-                        signature: 50, // committee signature
+                        content: FinalizationContent::new(
+                            finalization_content.height,
+                            finalization_content.block,
+                        ),
+                        signature: self.crypto.aggregate(committee.into_values().collect()), // committee signature
                     }))
-                };
+                }
             }
         }
         stuff
     }
 }
-/*
+
+/// Group the signatures of shares by the content they sign, keeping a single
+/// signature per signer.
 pub fn aggregate<T: Ord>(
-    shares: Box<dyn Iterator<Item = Signed<T, u8>>>,
-) -> BTreeMap<T, BTreeSet<u8>> {
+    shares: impl Iterator<Item = (T, BasicSignature)>,
+) -> BTreeMap<T, BTreeMap<u8, BasicSignature>> {
     shares.fold(
-        BTreeMap::<T, BTreeSet<u8>>::new(),
-        |mut grouped_shares, share| {
-            match grouped_shares.get_mut(&share.content) {
-                Some(existing) => {
-                    existing.insert(share.signature);
-                }
-                None => {
-                    let mut new_set = BTreeSet::<u8>::new();
-                    new_set.insert(share.signature);
-                    grouped_shares.insert(share.content, new_set);
-                }
-            };
+        BTreeMap::<T, BTreeMap<u8, BasicSignature>>::new(),
+        |mut grouped_shares, (content, signature)| {
+            grouped_shares
+                .entry(content)
+                .or_default()
+                .insert(signature.signer, signature);
             grouped_shares
         },
     )
 }
-*/
+
 /*
 fn _group_shares_and_acks(
     grouped_shares_separated_from_acks: BTreeMap<NotarizationShareContent, BTreeSet<u8>>,
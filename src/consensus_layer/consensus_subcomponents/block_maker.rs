@@ -1,19 +1,32 @@
+use std::{
+    convert::TryInto,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use crate::{
     consensus_layer::{
-        artifacts::ConsensusMessage, consensus_subcomponents::goodifier::IMadeABlockArtifact,
-        height_index::Height, pool_reader::PoolReader,
+        artifacts::ConsensusMessage,
+        consensus_subcomponents::{
+            goodifier::{block_is_good, IMadeABlockArtifact},
+            random_beacon_maker::RandomBeacon,
+        },
+        height_index::Height,
+        payload_store::PayloadStore,
+        pool_reader::PoolReader,
     },
-    crypto::{Hashed, Signed, TurboHash},
-    time_source::system_time_now,
+    crypto::{to_bytes, BasicSignature, CryptoHash, CryptoProvider, Domain, Hashed, Signed},
+    time_source::{system_time_now, Time},
     SubnetParams,
 };
 
-#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Payload {
-    //#[serde(with = "serde_bytes")]
-    pl: String,
+    bytes: Vec<u8>,
 }
 
 /*impl Default for Payload {
@@ -23,10 +36,19 @@ pub struct Payload {
 }*/
 
 impl Payload {
+    // the bytes are random, so that the payloads of different blocks have different hashes
     pub fn new(size: usize) -> Self {
-        Self {
-            pl: "a".repeat(size),
-        }
+        let mut bytes = vec![0; size];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        Self { bytes }
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self { bytes }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
     }
 }
 
@@ -36,59 +58,82 @@ impl Payload {
 pub struct Block {
     // the parent block that this block extends, forming a block chain
     pub parent: String,
-    // the payload of the block
-    pub payload: Payload,
+    // the hash of the payload of the block, which is transferred separately
+    pub payload_hash: CryptoHash,
     // the height of the block, which is the height of the parent + 1
     pub height: u64,
     // rank indicates the rank of the block maker that created this block
     pub rank: u8,
 }
 
+impl Domain for Block {
+    const DOMAIN: &'static str = "block";
+}
+
 impl Block {
     // Create a new block
-    pub fn new(parent: String, payload: Payload, height: u64, rank: u8) -> Self {
+    pub fn new(parent: String, payload_hash: CryptoHash, height: u64, rank: u8) -> Self {
         Block {
             parent,
-            payload,
+            payload_hash,
             height,
             rank,
         }
     }
 }
 
-impl TurboHash for Block {
-    fn tubro_hash(&self) -> String {
-        format!("block{}", self.height)
-    }
-}
-
 /// HashedBlock contains a Block together with its hash
 pub type HashedBlock = Hashed<Block>;
 
-pub type BlockProposal = Signed<HashedBlock, u8>;
+impl Domain for HashedBlock {
+    const DOMAIN: &'static str = "block_proposal";
+}
 
-pub struct RandomBeacon {}
+pub type BlockProposal = Signed<HashedBlock, BasicSignature>;
 
 pub struct BlockMaker {
     node_id: u8,
     subnet_params: SubnetParams,
+    crypto: Arc<dyn CryptoProvider>,
+    payloads: Arc<RwLock<PayloadStore>>,
+    start_time: Time,
 }
 
 impl BlockMaker {
-    pub fn new(node_id: u8, subnet_params: SubnetParams) -> Self {
+    pub fn new(
+        node_id: u8,
+        subnet_params: SubnetParams,
+        crypto: Arc<dyn CryptoProvider>,
+        payloads: Arc<RwLock<PayloadStore>>,
+    ) -> Self {
         Self {
             node_id,
             subnet_params,
+            crypto,
+            payloads,
+            start_time: system_time_now(),
         }
     }
 
     pub fn on_state_change(&self, pool: &PoolReader<'_>) -> Vec<ConsensusMessage> {
         // println!("\n########## Block maker ##########");
         let my_node_id = self.node_id;
-        let (beacon, parent) =
-            get_dependencies(pool, self.subnet_params.fast_internet_computer_consensus).unwrap();
+        let (beacon, parent) = match get_dependencies(
+            pool,
+            self.crypto.as_ref(),
+            self.subnet_params.fast_internet_computer_consensus,
+        ) {
+            Some(dependencies) => dependencies,
+            None => return vec![],
+        };
         let height: u64 = parent.height + 1;
-        let rank = self.get_block_maker_rank(height, &beacon, my_node_id);
+        let rank = get_block_maker_rank(
+            self.crypto.as_ref(),
+            &beacon,
+            my_node_id,
+            self.subnet_params.total_nodes_number,
+        );
+        // println!("Local rank for height {} is: {}", height, rank);
 
         if !already_proposed(pool, height, my_node_id)
             && !self.is_better_block_proposal_available(pool, height, rank)
@@ -96,7 +141,7 @@ impl BlockMaker {
                 pool,
                 height,
                 rank,
-                my_node_id,
+                self.start_time,
                 self.subnet_params.artifact_delay,
             )
         {
@@ -122,13 +167,6 @@ impl BlockMaker {
         }
     }
 
-    fn get_block_maker_rank(&self, height: u64, _beacon: &RandomBeacon, my_node_id: u8) -> u8 {
-        //let rank =
-        ((height + my_node_id as u64 - 2) % self.subnet_params.total_nodes_number as u64) as u8
-        // println!("Local rank for height {} is: {}", height, rank);
-        //rank
-    }
-
     /// Return true if the validated pool contains a better (lower ranked) block
     /// proposal than the given rank, for the given height.
     fn is_better_block_proposal_available(
@@ -150,7 +188,7 @@ impl BlockMaker {
         rank: u8,
         parent: Block,
     ) -> Option<BlockProposal> {
-        let parent_hash = Hashed::crypto_hash(&parent);
+        let parent_hash = self.crypto.hash(&to_bytes(&parent));
         let height: u64 = parent.height + 1;
         self.construct_block_proposal(parent_hash, height, rank)
     }
@@ -166,20 +204,67 @@ impl BlockMaker {
         rank: u8,
     ) -> Option<BlockProposal> {
         let payload = Payload::new(self.subnet_params.blocksize);
-        let block = Block::new(parent_hash, payload, height, rank);
+        let payload_hash = self.crypto.hash(payload.as_bytes());
+        // the peers download the payload from the store once they receive the proposal
+        self.payloads
+            .write()
+            .unwrap()
+            .insert(payload_hash.clone(), height, payload);
+        let block = Block::new(parent_hash, payload_hash, height, rank);
+        let content = Hashed {
+            hash: self.crypto.hash(&to_bytes(&block)),
+            value: block,
+        };
         Some(BlockProposal {
-            signature: self.node_id,
-            content: Hashed::new(block),
+            signature: self.crypto.sign(&to_bytes(&content)),
+            content,
         })
     }
 }
 
+/// Return the rank of the given replica at the height following the given
+/// random beacon. Ranks are obtained by shuffling the replicas with a
+/// Fisher-Yates shuffle seeded by the hash of the beacon, so that every replica
+/// computes the same permutation.
+pub fn get_block_maker_rank(
+    crypto: &dyn CryptoProvider,
+    beacon: &RandomBeacon,
+    node_id: u8,
+    total_nodes_number: u8,
+) -> u8 {
+    let seed = crypto.hash(&to_bytes(beacon));
+    let mut replicas: Vec<u8> = (1..=total_nodes_number).collect();
+    for i in (1..replicas.len()).rev() {
+        let digest = Sha256::digest(format!("{}-{}", seed, i).as_bytes());
+        let random = u64::from_be_bytes(digest[..8].try_into().unwrap());
+        replicas.swap(i, (random % (i as u64 + 1)) as usize);
+    }
+    replicas
+        .iter()
+        .position(|replica| *replica == node_id)
+        .expect("replica is not part of the subnet") as u8
+}
+
+/// Return the block of height 0, which is known to all replicas and extended
+/// by the proposals of height 1.
+pub fn genesis_block() -> Block {
+    Block {
+        parent: String::from("block-1"),
+        payload_hash: String::from("payload-1"),
+        height: 0,
+        rank: 0,
+    }
+}
+
 // Return the parent random beacon and block of the latest round for which
 // this node might propose a block.
-// Return None otherwise.
+// Return None if the random beacon of the parent height is not available yet.
+// With FICC, only "good" blocks can be extended, so return None if no notarized
+// block at the latest notarized height is "good" yet.
 fn get_dependencies(
     pool: &PoolReader<'_>,
-    _is_fast_internet_computer_consensus: bool,
+    crypto: &dyn CryptoProvider,
+    is_fast_internet_computer_consensus: bool,
 ) -> Option<(RandomBeacon, Block)> {
     let notarized_height = pool.get_notarized_height();
     // println!("Last block notarized at height: {}", notarized_height);
@@ -188,22 +273,19 @@ fn get_dependencies(
     // and then choose the one with the smallest rank among the "good" ones
     let parent = pool
         .get_notarized_blocks(notarized_height)
+        .filter(|block| !is_fast_internet_computer_consensus || block_is_good(pool, crypto, block))
         .min_by(|block1, block2| block1.rank.cmp(&block2.rank));
-    match parent {
+    let parent = match parent {
         Some(parent) => {
             // println!("Parent block: {:?}", parent);
-            Some((RandomBeacon {}, parent))
+            parent
         }
-        None => Some((
-            RandomBeacon {},
-            Block {
-                parent: String::from("block-1"),
-                payload: Payload::new(3),
-                height: 0,
-                rank: 0,
-            },
-        )),
-    }
+        None if !is_fast_internet_computer_consensus || notarized_height == 0 => genesis_block(),
+        None => return None,
+    };
+    // the ranks at a height are determined by the random beacon of the previous height
+    let beacon = pool.get_random_beacon(parent.height)?;
+    Some((beacon, parent))
 }
 
 // Return true if this node has already made a proposal at the given height.
@@ -212,49 +294,29 @@ fn already_proposed(pool: &PoolReader<'_>, h: u64, this_node: u8) -> bool {
         .validated()
         .block_proposal()
         .get_by_height(h)
-        .any(|p| p.signature == this_node)
+        .any(|p| p.signature.signer == this_node)
 }
 
 // Return true if the time since round start is greater than the required block
-// maker delay for the given rank.
+// maker delay for the given rank. Without a notarization of the previous height,
+// e.g. at height 1, the round is considered to start when the block maker started.
 fn is_time_to_make_block(
-    _pool: &PoolReader<'_>,
-    _height: u64,
+    pool: &PoolReader<'_>,
+    height: u64,
     rank: u8,
-    _node_id: u8,
-    _proposer_delay: u64,
+    start_time: Time,
+    proposer_delay: u64,
 ) -> bool {
-    rank == 0
-    /*
-    let block_maker_delay = get_block_maker_delay(rank, proposer_delay);
-    let just_make_sure_omg = Duration::from_secs(60);
-    match pool.get_round_start_time(height) {
-        Some(start_time) => {
-            let current_time = time_source.get_relative_time();
-            if current_time + just_make_sure_omg >= start_time + block_maker_delay {
-                return true;
-            }
-            false
-        }
-        None => {
-            // if there is no previous notarization, node 1 proposes the first block (has rank 0 in the first round)
-            if node_id == 1 && rank == 0 {
-                return true;
-            }
-            false
-        }
-    }*/
+    let round_start = pool.get_round_start_time(height).unwrap_or(start_time);
+    system_time_now() >= round_start + get_block_maker_delay(rank, proposer_delay)
 }
 
 /// Calculate the required delay for block making based on the block maker's
-/// rank.
-/*fn get_block_maker_delay(rank: u8, proposer_delay: u64) -> Duration {
-    if rank == 0 {
-        Duration::from_millis(0)
-    } else {
-        (Duration::from_millis(proposer_delay) * rank as u32) + Duration::from_secs(60)
-    }
-}*/
+/// rank, so that lower ranked replicas only propose if the better ranked
+/// ones failed to.
+fn get_block_maker_delay(rank: u8, proposer_delay: u64) -> Duration {
+    Duration::from_millis(proposer_delay) * rank as u32
+}
 
 /// Return the validated block proposals with the lowest rank at height `h`, if
 /// there are any. Else return `None`.
@@ -278,3 +340,88 @@ pub fn find_lowest_ranked_proposals(pool: &PoolReader<'_>, h: Height) -> Vec<Blo
         );
     best_proposals
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::{
+        consensus_layer::consensus_subcomponents::random_beacon_maker::{
+            genesis_random_beacon, RandomBeaconContent,
+        },
+        crypto::{CryptoHashOf, FakeCryptoProvider, ThresholdSignature},
+    };
+
+    fn beacon(height: Height) -> RandomBeacon {
+        RandomBeacon {
+            content: RandomBeaconContent::new(height, CryptoHashOf::from(String::from("parent"))),
+            signature: ThresholdSignature {
+                signature: vec![height as u8],
+            },
+        }
+    }
+
+    fn ranks(
+        crypto: &dyn CryptoProvider,
+        beacon: &RandomBeacon,
+        total_nodes_number: u8,
+    ) -> Vec<u8> {
+        (1..=total_nodes_number)
+            .map(|node_id| get_block_maker_rank(crypto, beacon, node_id, total_nodes_number))
+            .collect()
+    }
+
+    #[test]
+    fn ranks_are_a_permutation_of_the_replicas() {
+        let crypto = FakeCryptoProvider::new(1, 10, 7);
+        for total_nodes_number in [1, 2, 4, 10] {
+            for height in 0..20 {
+                let ranks = ranks(&crypto, &beacon(height), total_nodes_number);
+                assert_eq!(
+                    ranks.into_iter().collect::<BTreeSet<_>>(),
+                    (0..total_nodes_number).collect::<BTreeSet<_>>()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn ranks_are_the_same_at_every_replica() {
+        // the ranks only depend on the beacon, not on the replica computing them
+        let beacon = genesis_random_beacon();
+        let expected = ranks(&FakeCryptoProvider::new(1, 4, 3), &beacon, 4);
+        for node_id in 2..=4 {
+            assert_eq!(
+                ranks(&FakeCryptoProvider::new(node_id, 4, 3), &beacon, 4),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn ranks_change_with_the_beacon() {
+        let crypto = FakeCryptoProvider::new(1, 10, 7);
+        let rankings: BTreeSet<Vec<u8>> = (0..20)
+            .map(|height| ranks(&crypto, &beacon(height), 10))
+            .collect();
+        assert!(rankings.len() > 1);
+        // every replica gets to be the block maker of rank 0 at some height
+        let leaders: BTreeSet<usize> = (0..200)
+            .map(|height| {
+                ranks(&crypto, &beacon(height), 4)
+                    .iter()
+                    .position(|rank| *rank == 0)
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(leaders.len(), 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "replica is not part of the subnet")]
+    fn replicas_outside_the_subnet_have_no_rank() {
+        let crypto = FakeCryptoProvider::new(1, 4, 3);
+        get_block_maker_rank(&crypto, &genesis_random_beacon(), 5, 4);
+    }
+}
@@ -0,0 +1,142 @@
+//! The catch-up package maker is responsible for creating catch-up package
+//! shares for the finalized heights at which a catch-up package is due. Once
+//! enough shares are available, the share aggregator combines them into a
+//! catch-up package, from which a replica that lags behind resumes without
+//! needing the artifacts below its height.
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    consensus_layer::{artifacts::ConsensusMessage, height_index::Height, pool_reader::PoolReader},
+    crypto::{
+        to_bytes, BasicSignature, CryptoHash, CryptoProvider, Domain, MultiSignature, Signed,
+    },
+    SubnetParams,
+};
+
+use super::{block_maker::HashedBlock, random_beacon_maker::RandomBeacon};
+
+/// CatchUpContent holds the values that are signed in a catch-up package
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct CatchUpContent {
+    // the finalized block of the height of the catch-up package
+    pub block: HashedBlock,
+    // the random beacon of the same height, which determines the ranks of the next height
+    pub random_beacon: RandomBeacon,
+    // reference to the state reached by delivering the finalized blocks up to the block
+    pub state_hash: CryptoHash,
+}
+
+impl Domain for CatchUpContent {
+    const DOMAIN: &'static str = "catch_up_content";
+}
+
+impl CatchUpContent {
+    pub fn height(&self) -> Height {
+        self.block.value.height
+    }
+}
+
+/// A catch-up package share is a multi-signature share on a catch-up content.
+/// If sufficiently many replicas create catch-up package shares, the shares can
+/// be aggregated into a full catch-up package.
+pub type CatchUpPackageShare = Signed<CatchUpContent, BasicSignature>;
+
+/// A catch-up package is a multi-signature on a CatchUpContent. It proves that
+/// the block it holds is finalized, so that a replica can continue from it
+/// without the blocks and artifacts of the heights below.
+pub type CatchUpPackage = Signed<CatchUpContent, MultiSignature>;
+
+/// The state reached by delivering a finalized block. As replicas do not
+/// execute the payloads, the state is a hash chain of the hashes of the
+/// delivered payloads.
+#[derive(Serialize)]
+struct State<'a> {
+    parent: &'a CryptoHash,
+    payload_hash: &'a CryptoHash,
+}
+
+impl Domain for State<'_> {
+    const DOMAIN: &'static str = "state";
+}
+
+pub struct CatchUpPackageMaker {
+    node_id: u8,
+    subnet_params: SubnetParams,
+    crypto: Arc<dyn CryptoProvider>,
+}
+
+impl CatchUpPackageMaker {
+    pub fn new(node_id: u8, subnet_params: SubnetParams, crypto: Arc<dyn CryptoProvider>) -> Self {
+        Self {
+            node_id,
+            subnet_params,
+            crypto,
+        }
+    }
+
+    /// Return a catch-up package share for the greatest finalized height at
+    /// which a catch-up package is due, if there is no catch-up package for it
+    /// yet and this replica has not already signed it.
+    pub fn on_state_change(&self, pool: &PoolReader<'_>) -> Vec<ConsensusMessage> {
+        // println!("\n########## Catch-up package maker ##########");
+        let catch_up_interval = self.subnet_params.catch_up_interval;
+        if catch_up_interval == 0 {
+            return vec![];
+        }
+        let finalized_height = pool.get_finalized_height();
+        let height = finalized_height - finalized_height % catch_up_interval;
+        if height <= pool.get_catch_up_height()
+            || pool
+                .get_catch_up_package_shares(height)
+                .any(|share| share.signature.signer == self.node_id)
+        {
+            return vec![];
+        }
+        match get_catch_up_content(pool, self.crypto.as_ref(), height) {
+            Some(content) => {
+                let signature = self.crypto.sign(&to_bytes(&content));
+                vec![ConsensusMessage::CatchUpPackageShare(CatchUpPackageShare {
+                    content,
+                    signature,
+                })]
+            }
+            None => vec![],
+        }
+    }
+}
+
+/// Return the content of the catch-up package of the given finalized height,
+/// if its random beacon and the finalized blocks since the latest catch-up
+/// package are in the pool.
+pub fn get_catch_up_content(
+    pool: &PoolReader<'_>,
+    crypto: &dyn CryptoProvider,
+    height: Height,
+) -> Option<CatchUpContent> {
+    let (base_height, base_state_hash) = match pool.get_catch_up_package() {
+        Some(catch_up_package) if catch_up_package.content.height() <= height => (
+            catch_up_package.content.height(),
+            catch_up_package.content.state_hash,
+        ),
+        _ => (0, CryptoHash::new()),
+    };
+    let blocks = pool.get_finalized_chain(base_height + 1, height)?;
+    let block = blocks.last()?.clone();
+    let state_hash = blocks.iter().fold(base_state_hash, |parent, block| {
+        crypto.hash(&to_bytes(&State {
+            parent: &parent,
+            payload_hash: &block.payload_hash,
+        }))
+    });
+    Some(CatchUpContent {
+        block: HashedBlock {
+            hash: crypto.hash(&to_bytes(&block)),
+            value: block,
+        },
+        random_beacon: pool.get_random_beacon(height)?,
+        state_hash,
+    })
+}
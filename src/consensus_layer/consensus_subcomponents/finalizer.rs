@@ -1,14 +1,14 @@
-use std::cell::RefCell;
+use std::{cell::RefCell, sync::Arc};
 
 use serde::{Deserialize, Serialize};
 
 use crate::{
     consensus_layer::{artifacts::ConsensusMessage, height_index::Height, pool_reader::PoolReader},
-    crypto::{CryptoHashOf, Hashed, Signed, TurboHash},
+    crypto::{to_bytes, BasicSignature, CryptoHashOf, CryptoProvider, Signed},
     SubnetParams,
 };
 
-use super::block_maker::Block;
+use super::{aggregator::FinalizationContent, block_maker::Block, goodifier::block_is_good};
 
 /// FinalizationShareContent holds the values that are signed in a finalization share
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
@@ -17,12 +17,6 @@ pub struct FinalizationShareContent {
     pub block: CryptoHashOf<Block>,
 }
 
-impl TurboHash for FinalizationShareContent {
-    fn tubro_hash(&self) -> String {
-        format!("FSh{}", self.height)
-    }
-}
-
 impl FinalizationShareContent {
     pub fn new(height: Height, block: CryptoHashOf<Block>) -> Self {
         FinalizationShareContent { height, block }
@@ -32,21 +26,23 @@ impl FinalizationShareContent {
 /// A finalization share is a multi-signature share on a finalization content.
 /// If sufficiently many replicas create finalization shares, the shares can be
 /// aggregated into a full finalization.
-pub type FinalizationShare = Signed<FinalizationShareContent, u8>;
+pub type FinalizationShare = Signed<FinalizationShareContent, BasicSignature>;
 
 pub struct Finalizer {
     node_id: u8,
-    _subnet_params: SubnetParams,
+    subnet_params: SubnetParams,
     _prev_finalized_height: RefCell<Height>,
+    crypto: Arc<dyn CryptoProvider>,
 }
 
 impl Finalizer {
     #[allow(clippy::too_many_arguments)]
-    pub fn new(node_id: u8, subnet_params: SubnetParams) -> Self {
+    pub fn new(node_id: u8, subnet_params: SubnetParams, crypto: Arc<dyn CryptoProvider>) -> Self {
         Self {
             node_id,
-            _subnet_params: subnet_params,
+            subnet_params,
             _prev_finalized_height: RefCell::new(0),
+            crypto,
         }
     }
 
@@ -61,15 +57,25 @@ impl Finalizer {
         for height in finalized_height + 1..=notarized_height {
             if !pool
                 .get_finalization_shares(height, height)
-                .any(|share| share.signature == 50 + self.node_id)
+                .any(|share| share.signature.signer == self.node_id)
             {
+                // with FICC, only a "good" block can be finalized
+                let block = match pool.get_notarized_blocks(height).find(|block| {
+                    !self.subnet_params.fast_internet_computer_consensus
+                        || block_is_good(pool, self.crypto.as_ref(), block)
+                }) {
+                    Some(block) => block,
+                    None => continue,
+                };
                 let content = FinalizationShareContent::new(
                     height,
-                    CryptoHashOf::new(Hashed::crypto_hash(
-                        &pool.get_notarized_blocks(height).next().unwrap(),
-                    )),
+                    CryptoHashOf::new(self.crypto.hash(&to_bytes(&block))),
                 );
-                let signature = 50 + self.node_id;
+                // the share signs the content of the finalization it contributes to
+                let signature = self.crypto.sign(&to_bytes(&FinalizationContent::new(
+                    content.height,
+                    content.block.clone(),
+                )));
 
                 stuff.push(ConsensusMessage::FinalizationShare(FinalizationShare {
                     content,
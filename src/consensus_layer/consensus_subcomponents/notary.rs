@@ -1,14 +1,27 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 
 use crate::{
     consensus_layer::{artifacts::ConsensusMessage, height_index::Height, pool_reader::PoolReader},
-    crypto::{CryptoHashOf, Hashed, Signed, TurboHash},
+    crypto::{to_bytes, BasicSignature, CryptoHashOf, CryptoProvider, Signed},
+    time_source::{system_time_now, Time},
     SubnetParams,
 };
 
-use super::block_maker::{Block, BlockProposal};
+use super::{
+    aggregator::{FinalizationContent, NotarizationContent},
+    block_maker::{Block, BlockProposal},
+};
+
+/// Rule deciding which of the notarization shares of a replica are also
+/// acknowledgements when FICC is used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckPolicy {
+    FirstChild,          // the first child of each block is acknowledged, as in the paper
+    FirstBlockPerHeight, // only the first block of each height is acknowledged
+}
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum NotarizationShareContent {
@@ -16,14 +29,15 @@ pub enum NotarizationShareContent {
     ICC(NotarizationShareContentICC), // content of notarization share when only Internet Computer Consensus is used
 }
 
-impl TurboHash for NotarizationShareContent {
-    fn tubro_hash(&self) -> String {
+impl NotarizationShareContent {
+    /// Return the content of the notarization that this share contributes to.
+    pub fn notarization_content(&self) -> NotarizationContent {
         match self {
-            Self::COD(stuf) => {
-                format!("NShCOD{}.{}", stuf.height, stuf.is_ack,)
+            Self::COD(share_content) => {
+                NotarizationContent::new(share_content.height, share_content.block.clone())
             }
-            Self::ICC(stuf) => {
-                format!("NShICC{}", stuf.height,)
+            Self::ICC(share_content) => {
+                NotarizationContent::new(share_content.height, share_content.block.clone())
             }
         }
     }
@@ -74,21 +88,58 @@ impl NotarizationShareContentCOD {
     }
 }
 
+/// NotarizationShareSignature holds the signature of a replica on the
+/// [NotarizationContent] of the notarized block. An acknowledgement also holds
+/// a signature on the [FinalizationContent] of the block, which can be
+/// aggregated with other acknowledgements into an FP-finalization.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct NotarizationShareSignature {
+    pub signer: u8,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+    pub ack_signature: Option<ByteBuf>,
+}
+
+impl NotarizationShareSignature {
+    /// Return the signature on the notarization content.
+    pub fn notarization_signature(&self) -> BasicSignature {
+        BasicSignature {
+            signer: self.signer,
+            signature: self.signature.clone(),
+        }
+    }
+
+    /// Return the signature on the finalization content, if the share is an
+    /// acknowledgement.
+    pub fn ack_signature(&self) -> Option<BasicSignature> {
+        self.ack_signature
+            .as_ref()
+            .map(|ack_signature| BasicSignature {
+                signer: self.signer,
+                signature: ack_signature.to_vec(),
+            })
+    }
+}
+
 /// A notarization share is a multi-signature share on a notarization content.
 /// If sufficiently many replicas create notarization shares, the shares can be
 /// aggregated into a full notarization.
-pub type NotarizationShare = Signed<NotarizationShareContent, u8>;
+pub type NotarizationShare = Signed<NotarizationShareContent, NotarizationShareSignature>;
 
 pub struct Notary {
     node_id: u8,
     subnet_params: SubnetParams,
+    crypto: Arc<dyn CryptoProvider>,
+    start_time: Time,
 }
 
 impl Notary {
-    pub fn new(node_id: u8, subnet_params: SubnetParams) -> Self {
+    pub fn new(node_id: u8, subnet_params: SubnetParams, crypto: Arc<dyn CryptoProvider>) -> Self {
         Self {
             node_id,
             subnet_params,
+            crypto,
+            start_time: system_time_now(),
         }
     }
 
@@ -100,34 +151,39 @@ impl Notary {
         // CoD implementation change: cannot use find_lowest_ranked_proposals.
         // in case there is only one 'good' block, it might not be the one with the lowest rank
         // therefore, we consider all proposals
-        for proposal in get_proposals(pool, height) {
-            let _rank = proposal.content.value.rank;
-            /*if self.time_to_notarize(pool, height, rank)
-            &&*/
-            if !self.is_proposal_already_notarized_by_me(pool, &proposal) {
-                if let Some(s) = self.notarize_block(pool, proposal) {
+        let mut proposals = get_proposals(pool, height);
+        // when several proposals can be notarized at once, the best ranked one is the first one acknowledged
+        proposals.sort_by_key(|proposal| proposal.content.value.rank);
+        for proposal in proposals {
+            let rank = proposal.content.value.rank;
+            if self.time_to_notarize(pool, height, rank)
+                && !self.is_proposal_already_notarized_by_me(pool, &proposal)
+            {
+                if let Some(s) = self.notarize_block(pool, &notarization_shares, proposal) {
                     // println!(
                     //     "\nCreated notarization share: {:?} for proposal of rank: {:?}",
                     //     s, rank
                     // );
-                    notarization_shares.push(ConsensusMessage::NotarizationShare(s));
+                    notarization_shares.push(s);
                 }
             }
         }
         notarization_shares
+            .into_iter()
+            .map(ConsensusMessage::NotarizationShare)
+            .collect()
     }
 
-    /// Return the time since round start, if it is greater than required
-    /// notarization delay for the given block rank, or None otherwise.
-    /*fn _time_to_notarize(&self, pool: &PoolReader<'_>, height: Height, rank: u8) -> bool {
+    /// Return true if the time since round start is greater than the required
+    /// notarization delay for the given block rank. Without a notarization of
+    /// the previous height, e.g. at height 1, the round is considered to start
+    /// when the notary started.
+    fn time_to_notarize(&self, pool: &PoolReader<'_>, height: Height, rank: u8) -> bool {
         let adjusted_notary_delay =
             get_adjusted_notary_delay(pool, height, rank, self.subnet_params.artifact_delay);
-        if let Some(start_time) = pool.get_round_start_time(height) {
-            let now = self._time_source.get_relative_time();
-            return now >= start_time + adjusted_notary_delay;
-        }
-        height == 1
-    }*/
+        let start_time = pool.get_round_start_time(height).unwrap_or(self.start_time);
+        system_time_now() >= start_time + adjusted_notary_delay
+    }
 
     /// Return true if this node has already published a notarization share
     /// for the given block proposal. Return false otherwise.
@@ -138,7 +194,7 @@ impl Notary {
     ) -> bool {
         let height = proposal.content.value.height;
         pool.get_notarization_shares(height)
-            .filter(|s| s.signature == self.node_id)
+            .filter(|s| s.signature.signer == self.node_id)
             .any(|s| match s.content {
                 NotarizationShareContent::COD(share_content) => {
                     proposal.content.hash.eq(share_content.block.get_ref())
@@ -149,31 +205,39 @@ impl Notary {
             })
     }
 
-    /// Notarize and return a `NotarizationShare` for the given block
+    /// Notarize and return a `NotarizationShare` for the given block. The
+    /// shares created in the current round of the notary are not in the pool
+    /// yet, so they are passed in `new_shares`.
     fn notarize_block(
         &self,
-        _pool: &PoolReader<'_>,
-        proposal: Signed<Hashed<Block>, u8>,
+        pool: &PoolReader<'_>,
+        new_shares: &[NotarizationShare],
+        proposal: BlockProposal,
     ) -> Option<NotarizationShare> {
-        let _height = proposal.content.value.height;
+        let height = proposal.content.value.height;
         let content: NotarizationShareContent = {
             if self.subnet_params.fast_internet_computer_consensus {
-                // CoD rule 1: first child of each block is acknowledged
-                let is_ack = true;
-                /*pool
-                .get_notarization_shares(height)
-                .filter(|s| s.signature == self.node_id) // filter out shares not sent by local replica */
-                /* NEW RULES: first block per height
-                .filter(|s| {
-                    if let NotarizationShareContent::COD(notarization_share_content_cod) = &s.content {
-                        notarization_share_content_cod.block_parent_hash == proposal.content.value.parent   // filter out shares for blocks that do not have the same parent of the block being proposed
-                    }
-                    else {
-                        panic!("no notarization shares of ICC variant when fast_internet_computer_consensus parameter is true");
-                    }
-                })*/
-                //.count()
-                //== 0; // set 'is_ack' to true if 'proposal' is the first child of its parent for which the local replica creates a notarization share, the latter is also an acknowledgement
+                // set 'is_ack' to true if no share of the local replica already acknowledges another block
+                // with the same parent (first child rule) or at the same height (first block per height rule)
+                let is_ack = !pool
+                    .get_notarization_shares(height)
+                    .chain(new_shares.iter().cloned())
+                    .filter(|s| s.signature.signer == self.node_id) // filter out shares not sent by local replica
+                    .any(|s| match s.content {
+                        NotarizationShareContent::COD(share_content) => {
+                            share_content.is_ack
+                                && match self.subnet_params.ack_policy {
+                                    AckPolicy::FirstChild => {
+                                        share_content.block_parent_hash
+                                            == proposal.content.value.parent
+                                    }
+                                    AckPolicy::FirstBlockPerHeight => true,
+                                }
+                        }
+                        NotarizationShareContent::ICC(_) => {
+                            panic!("no notarization shares of ICC variant when fast_internet_computer_consensus parameter is true")
+                        }
+                    });
                 NotarizationShareContent::COD(NotarizationShareContentCOD::new(
                     proposal.content.value.height,
                     CryptoHashOf::from(proposal.content.hash),
@@ -188,7 +252,26 @@ impl Notary {
                 ))
             }
         };
-        let signature = self.node_id;
+        let notarization_content = content.notarization_content();
+        // an acknowledgement also signs the FP-finalization content of the block, as acknowledging a
+        // block is a stronger statement than notarizing it
+        let ack_signature = match &content {
+            NotarizationShareContent::COD(share_content) if share_content.is_ack => {
+                let finalization_content = FinalizationContent::new_fp(
+                    notarization_content.height,
+                    notarization_content.block.clone(),
+                );
+                Some(ByteBuf::from(
+                    self.crypto.sign(&to_bytes(&finalization_content)).signature,
+                ))
+            }
+            _ => None,
+        };
+        let signature = NotarizationShareSignature {
+            signer: self.node_id,
+            signature: self.crypto.sign(&to_bytes(&notarization_content)).signature,
+            ack_signature,
+        };
         Some(NotarizationShare { content, signature })
     }
 }
@@ -245,7 +328,8 @@ pub fn get_adjusted_notary_delay(
     // round.  This exponential backoff does not apply to block rank 0.
     let finalized_height = pool.get_finalized_height();
     let ranked_delay = notarization_delay as f32 * rank as f32;
-    let finality_gap = (pool.get_notarized_height() - finalized_height) as i32;
+    // a finalization, e.g. an FP-finalization, can be validated before the notarization of its block
+    let finality_gap = pool.get_notarized_height().saturating_sub(finalized_height) as i32;
     let finality_adjusted_delay = (ranked_delay * 1.5_f32.powi(finality_gap)) as u64;
     Duration::from_millis(finality_adjusted_delay)
 }
@@ -0,0 +1,365 @@
+//! The purger is responsible for removing the artifacts that are no longer
+//! needed from the consensus pool, so that its size stays bounded on long runs.
+//! Below the purge height, which is `k` heights below the latest finalized
+//! height, only the finalized blocks and their notarizations and finalizations
+//! are kept. Once a catch-up package is validated, all artifacts below its
+//! height are removed, as a replica can continue from the catch-up package.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    consensus_layer::{
+        artifacts::{ChangeAction, ChangeSet, ConsensusMessage, ConsensusMessageHashable},
+        height_index::{Height, HeightIndexedPool, HeightRange},
+        payload_store::PayloadStore,
+        pool_reader::PoolReader,
+    },
+    crypto::CryptoHashOf,
+    SubnetParams,
+};
+
+use super::block_maker::Block;
+
+pub struct Purger {
+    _node_id: u8,
+    subnet_params: SubnetParams,
+    prev_purge_height: RefCell<Height>,
+    prev_catch_up_height: RefCell<Height>,
+    payloads: Arc<RwLock<PayloadStore>>,
+}
+
+impl Purger {
+    pub fn new(
+        node_id: u8,
+        subnet_params: SubnetParams,
+        payloads: Arc<RwLock<PayloadStore>>,
+    ) -> Self {
+        Self {
+            _node_id: node_id,
+            subnet_params,
+            prev_purge_height: RefCell::new(1),
+            prev_catch_up_height: RefCell::new(0),
+            payloads,
+        }
+    }
+
+    /// Return the removal of the artifacts below the purge height, if the purge
+    /// height increased since the last purge, or of all the artifacts below
+    /// the latest catch-up package, if there is a new one.
+    pub fn on_state_change(&self, pool: &PoolReader<'_>) -> ChangeSet {
+        // println!("\n########## Purger ##########");
+        let catch_up_height = pool.get_catch_up_height();
+        if catch_up_height > *self.prev_catch_up_height.borrow() {
+            let mut change_set = purge_unvalidated(pool, catch_up_height);
+            change_set.append(&mut purge_below_catch_up_package(pool, catch_up_height));
+            self.payloads.write().unwrap().remove_below(catch_up_height);
+            *self.prev_catch_up_height.borrow_mut() = catch_up_height;
+            // println!("Purged {} artifacts below catch-up package of height {}", change_set.len(), catch_up_height);
+            return change_set;
+        }
+        let purge_height = pool
+            .get_finalized_height()
+            .saturating_sub(self.subnet_params.purge_depth);
+        let prev_purge_height = *self.prev_purge_height.borrow();
+        if purge_height <= prev_purge_height {
+            return vec![];
+        }
+        let mut change_set = Vec::new();
+        change_set.append(&mut purge_unvalidated(pool, purge_height));
+        change_set.append(&mut purge_validated(pool, purge_height));
+        // the payloads of the finalized blocks are kept along with their proposals
+        let mut payloads = self.payloads.write().unwrap();
+        for change_action in change_set.iter() {
+            if let ChangeAction::RemoveFromValidated(ConsensusMessage::BlockProposal(proposal)) =
+                change_action
+            {
+                payloads.remove(&proposal.content.value.payload_hash);
+            }
+        }
+        *self.prev_purge_height.borrow_mut() = purge_height;
+        // println!("Purged {} artifacts below height {}", change_set.len(), purge_height);
+        change_set
+    }
+}
+
+/// Remove all the unvalidated artifacts below the purge height, as they are
+/// not needed to make progress anymore.
+fn purge_unvalidated(pool: &PoolReader<'_>, purge_height: Height) -> ChangeSet {
+    pool.pool()
+        .unvalidated()
+        .artifacts
+        .values()
+        .map(|artifact| artifact.message.clone())
+        .filter(|msg| msg.get_id().height < purge_height)
+        .map(ChangeAction::RemoveFromUnvalidated)
+        .collect()
+}
+
+/// Remove the validated artifacts below the purge height, except for the
+/// finalized blocks and their notarizations and finalizations. Artifacts may be
+/// validated late, below the previous purge height, so all heights below the
+/// purge height are checked.
+fn purge_validated(pool: &PoolReader<'_>, purge_height: Height) -> ChangeSet {
+    let validated = pool.pool().validated();
+    // the finalized blocks kept by the previous purges are the lowest blocks in the pool
+    let lowest_height = [
+        validated.block_proposal().height_range(),
+        validated.notarization().height_range(),
+        validated.finalization().height_range(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|range| range.min)
+    .min()
+    .unwrap_or(purge_height);
+    let finalized_hashes = get_finalized_hashes(pool, lowest_height, purge_height);
+    let is_finalized = |height: Height, block: &CryptoHashOf<Block>| {
+        finalized_hashes.get(&height) == Some(block.get_ref())
+    };
+    let mut to_remove: Vec<ConsensusMessage> = Vec::new();
+
+    if let Some(range) = below(validated.block_proposal(), purge_height) {
+        to_remove.extend(
+            validated
+                .block_proposal()
+                .get_by_height_range(range)
+                .filter(|proposal| {
+                    !is_finalized(
+                        proposal.content.value.height,
+                        &CryptoHashOf::from(proposal.content.hash.clone()),
+                    )
+                })
+                .map(ConsensusMessage::BlockProposal),
+        );
+    }
+    if let Some(range) = below(validated.notarization(), purge_height) {
+        to_remove.extend(
+            validated
+                .notarization()
+                .get_by_height_range(range)
+                .filter(|notarization| {
+                    !is_finalized(notarization.content.height, &notarization.content.block)
+                })
+                .map(ConsensusMessage::Notarization),
+        );
+    }
+    if let Some(range) = below(validated.finalization(), purge_height) {
+        to_remove.extend(
+            validated
+                .finalization()
+                .get_by_height_range(range)
+                .filter(|finalization| {
+                    !is_finalized(finalization.content.height, &finalization.content.block)
+                })
+                .map(ConsensusMessage::Finalization),
+        );
+    }
+    if let Some(range) = below(validated.notarization_share(), purge_height) {
+        to_remove.extend(
+            validated
+                .notarization_share()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::NotarizationShare),
+        );
+    }
+    if let Some(range) = below(validated.finalization_share(), purge_height) {
+        to_remove.extend(
+            validated
+                .finalization_share()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::FinalizationShare),
+        );
+    }
+    if let Some(range) = below(validated.random_beacon_share(), purge_height) {
+        to_remove.extend(
+            validated
+                .random_beacon_share()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::RandomBeaconShare),
+        );
+    }
+    // the ranks of the heights above the purge height only depend on the random beacons above it
+    if let Some(range) = below(validated.random_beacon(), purge_height) {
+        to_remove.extend(
+            validated
+                .random_beacon()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::RandomBeacon),
+        );
+    }
+    if let Some(range) = below(validated.goodness_artifact(), purge_height) {
+        to_remove.extend(
+            validated
+                .goodness_artifact()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::GoodnessArtifact),
+        );
+    }
+    if let Some(range) = below(validated.i_made_a_block_artifact(), purge_height) {
+        to_remove.extend(
+            validated
+                .i_made_a_block_artifact()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::IMadeABlockArtifact),
+        );
+    }
+    // the evidence of equivocations remains available through the log of the pool
+    if let Some(range) = below(validated.equivocation_evidence(), purge_height) {
+        to_remove.extend(
+            validated
+                .equivocation_evidence()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::EquivocationEvidence),
+        );
+    }
+    to_remove
+        .into_iter()
+        .map(ChangeAction::RemoveFromValidated)
+        .collect()
+}
+
+/// Remove all the validated artifacts below the height of the latest catch-up
+/// package, including the finalized blocks and the previous catch-up packages.
+fn purge_below_catch_up_package(pool: &PoolReader<'_>, catch_up_height: Height) -> ChangeSet {
+    let validated = pool.pool().validated();
+    let mut to_remove: Vec<ConsensusMessage> = Vec::new();
+    if let Some(range) = below(validated.block_proposal(), catch_up_height) {
+        to_remove.extend(
+            validated
+                .block_proposal()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::BlockProposal),
+        );
+    }
+    if let Some(range) = below(validated.notarization(), catch_up_height) {
+        to_remove.extend(
+            validated
+                .notarization()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::Notarization),
+        );
+    }
+    if let Some(range) = below(validated.finalization(), catch_up_height) {
+        to_remove.extend(
+            validated
+                .finalization()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::Finalization),
+        );
+    }
+    if let Some(range) = below(validated.notarization_share(), catch_up_height) {
+        to_remove.extend(
+            validated
+                .notarization_share()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::NotarizationShare),
+        );
+    }
+    if let Some(range) = below(validated.finalization_share(), catch_up_height) {
+        to_remove.extend(
+            validated
+                .finalization_share()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::FinalizationShare),
+        );
+    }
+    if let Some(range) = below(validated.random_beacon_share(), catch_up_height) {
+        to_remove.extend(
+            validated
+                .random_beacon_share()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::RandomBeaconShare),
+        );
+    }
+    if let Some(range) = below(validated.random_beacon(), catch_up_height) {
+        to_remove.extend(
+            validated
+                .random_beacon()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::RandomBeacon),
+        );
+    }
+    if let Some(range) = below(validated.goodness_artifact(), catch_up_height) {
+        to_remove.extend(
+            validated
+                .goodness_artifact()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::GoodnessArtifact),
+        );
+    }
+    if let Some(range) = below(validated.i_made_a_block_artifact(), catch_up_height) {
+        to_remove.extend(
+            validated
+                .i_made_a_block_artifact()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::IMadeABlockArtifact),
+        );
+    }
+    if let Some(range) = below(validated.equivocation_evidence(), catch_up_height) {
+        to_remove.extend(
+            validated
+                .equivocation_evidence()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::EquivocationEvidence),
+        );
+    }
+    if let Some(range) = below(validated.catch_up_package_share(), catch_up_height) {
+        to_remove.extend(
+            validated
+                .catch_up_package_share()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::CatchUpPackageShare),
+        );
+    }
+    if let Some(range) = below(validated.catch_up_package(), catch_up_height) {
+        to_remove.extend(
+            validated
+                .catch_up_package()
+                .get_by_height_range(range)
+                .map(ConsensusMessage::CatchUpPackage),
+        );
+    }
+    to_remove
+        .into_iter()
+        .map(ChangeAction::RemoveFromValidated)
+        .collect()
+}
+
+/// Return the range of heights from the lowest artifact in the pool to the
+/// purge height (excluded), if the pool holds artifacts of this type.
+fn below<T>(pool: &dyn HeightIndexedPool<T>, purge_height: Height) -> Option<HeightRange> {
+    pool.height_range()
+        .map(|range| HeightRange::new(range.min, purge_height - 1))
+}
+
+/// Return the hashes of the finalized blocks from the lowest height to the
+/// purge height (excluded), by height. Heights may be finalized implicitly
+/// by the finalization of a descendant, so the hashes are obtained by following
+/// the parents of the latest finalized block.
+fn get_finalized_hashes(
+    pool: &PoolReader<'_>,
+    lowest_height: Height,
+    purge_height: Height,
+) -> BTreeMap<Height, String> {
+    let mut finalized_hashes = BTreeMap::new();
+    let mut height = pool.get_finalized_height();
+    let mut hash = pool.get_finalized_block_hash_at_height(height);
+    while let Some(block_hash) = hash {
+        if height < lowest_height {
+            break;
+        }
+        hash = pool
+            .get_block(&CryptoHashOf::from(block_hash.clone()), height)
+            .ok()
+            .map(|block| block.parent);
+        if height < purge_height {
+            finalized_hashes.insert(height, block_hash);
+        }
+        height -= 1;
+    }
+    finalized_hashes
+}
@@ -0,0 +1,90 @@
+//! The random beacon maker is responsible for creating random beacon shares
+//! for the height following the latest random beacon. Once enough shares are
+//! available, the share aggregator combines them into the random beacon of
+//! that height, which determines the block maker ranks of the next height.
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    consensus_layer::{artifacts::ConsensusMessage, height_index::Height, pool_reader::PoolReader},
+    crypto::{
+        to_bytes, CryptoHashOf, CryptoProvider, Domain, Signed, ThresholdSignature,
+        ThresholdSignatureShare,
+    },
+};
+
+/// RandomBeaconContent holds the values that are signed in a random beacon
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct RandomBeaconContent {
+    pub height: Height,
+    pub parent: CryptoHashOf<RandomBeacon>,
+}
+
+impl Domain for RandomBeaconContent {
+    const DOMAIN: &'static str = "random_beacon_content";
+}
+
+impl RandomBeaconContent {
+    pub fn new(height: Height, parent: CryptoHashOf<RandomBeacon>) -> Self {
+        RandomBeaconContent { height, parent }
+    }
+}
+
+/// A random beacon share is a threshold signature share on a random beacon
+/// content. If sufficiently many replicas create random beacon shares, the
+/// shares can be combined into a full random beacon.
+pub type RandomBeaconShare = Signed<RandomBeaconContent, ThresholdSignatureShare>;
+
+/// A random beacon is a threshold signature on a RandomBeaconContent. As the
+/// threshold signature is unique, so is the random beacon of each height.
+pub type RandomBeacon = Signed<RandomBeaconContent, ThresholdSignature>;
+
+impl Domain for RandomBeacon {
+    const DOMAIN: &'static str = "random_beacon";
+}
+
+/// Return the random beacon of height 0, which is known to all replicas.
+pub fn genesis_random_beacon() -> RandomBeacon {
+    RandomBeacon {
+        content: RandomBeaconContent::new(0, CryptoHashOf::from(String::new())),
+        signature: ThresholdSignature { signature: vec![] },
+    }
+}
+
+pub struct RandomBeaconMaker {
+    node_id: u8,
+    crypto: Arc<dyn CryptoProvider>,
+}
+
+impl RandomBeaconMaker {
+    pub fn new(node_id: u8, crypto: Arc<dyn CryptoProvider>) -> Self {
+        Self { node_id, crypto }
+    }
+
+    /// Return a random beacon share for the height following the latest random
+    /// beacon, if the block at the height of the latest random beacon is
+    /// notarized and this node has not created a share yet.
+    pub fn on_state_change(&self, pool: &PoolReader<'_>) -> Vec<ConsensusMessage> {
+        // println!("\n########## Random beacon maker ##########");
+        let beacon = pool.get_random_beacon_tip();
+        let height = beacon.content.height + 1;
+        if pool.get_notarized_height() < beacon.content.height
+            || pool
+                .get_random_beacon_shares(height)
+                .any(|share| share.signature.signer == self.node_id)
+        {
+            return vec![];
+        }
+        let content = RandomBeaconContent::new(
+            height,
+            CryptoHashOf::from(self.crypto.hash(&to_bytes(&beacon))),
+        );
+        let signature = self.crypto.threshold_sign_share(&to_bytes(&content));
+        vec![ConsensusMessage::RandomBeaconShare(RandomBeaconShare {
+            content,
+            signature,
+        })]
+    }
+}
@@ -1,29 +1,521 @@
 use std::{
-    collections::BTreeMap,
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, HashMap},
     sync::{Arc, RwLock},
+    time::Duration,
 };
 
+use crossbeam_channel::Sender;
+use serde::{Deserialize, Serialize};
+
 use crate::{
     consensus_layer::{
-        artifacts::{ChangeAction, ChangeSet, ConsensusMessage, IntoInner},
+        artifacts::{
+            ChangeAction, ChangeSet, ConsensusMessage, ConsensusMessageHashable,
+            ConsensusMessageId, IntoInner, InvalidArtifactReason,
+        },
         consensus::RoundRobin,
         height_index::Height,
+        payload_store::PayloadStore,
         pool_reader::PoolReader,
     },
-    FinalizationType, HeightMetrics,
+    crypto::{to_bytes, ConsensusMessageHash, CryptoHashOf, CryptoProvider},
+    time_source::{system_time_now, Time},
+    FinalizationType, HeightMetrics, SubnetParams,
+};
+
+use super::{
+    acknowledger::is_parent_finalized,
+    aggregator::{Finalization, FinalizationContent},
+    block_maker::{genesis_block, get_block_maker_rank, Block, BlockProposal},
+    catch_up_package_maker::{get_catch_up_content, CatchUpPackage, CatchUpPackageShare},
+    notary::{NotarizationShare, NotarizationShareContent},
 };
 
+/// Evidence that a replica signed two conflicting artifacts at the same height.
+/// The artifacts are ordered by hash, so that all replicas detecting the same
+/// equivocation create the same evidence.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EquivocationEvidence {
+    BlockProposal(BlockProposal, BlockProposal),
+    NotarizationShare(NotarizationShare, NotarizationShare),
+}
+
+impl EquivocationEvidence {
+    pub fn block_proposals(first: BlockProposal, second: BlockProposal) -> Self {
+        let (first, second) = ordered_by_hash(first, second);
+        Self::BlockProposal(first, second)
+    }
+
+    pub fn notarization_shares(first: NotarizationShare, second: NotarizationShare) -> Self {
+        let (first, second) = ordered_by_hash(first, second);
+        Self::NotarizationShare(first, second)
+    }
+
+    /// Return the replica that signed both artifacts.
+    pub fn signer(&self) -> u8 {
+        match self {
+            Self::BlockProposal(first, _) => first.signature.signer,
+            Self::NotarizationShare(first, _) => first.signature.signer,
+        }
+    }
+
+    pub fn height(&self) -> Height {
+        match self {
+            Self::BlockProposal(first, _) => first.content.value.height,
+            Self::NotarizationShare(first, _) => first.content.notarization_content().height,
+        }
+    }
+
+    /// Return true if the artifacts are signed by the same replica and conflict with each other.
+    pub fn is_conflicting(&self) -> bool {
+        match self {
+            Self::BlockProposal(first, second) => are_conflicting_proposals(first, second),
+            Self::NotarizationShare(first, second) => {
+                are_conflicting_notarization_shares(first, second)
+            }
+        }
+    }
+}
+
+fn ordered_by_hash<T: ConsensusMessageHashable>(first: T, second: T) -> (T, T) {
+    if first.get_cm_hash().digest() <= second.get_cm_hash().digest() {
+        (first, second)
+    } else {
+        (second, first)
+    }
+}
+
+/// A block maker proposes at most one block per height.
+fn are_conflicting_proposals(first: &BlockProposal, second: &BlockProposal) -> bool {
+    first.signature.signer == second.signature.signer
+        && first.content.value.height == second.content.value.height
+        && first.content.hash != second.content.hash
+}
+
+/// A notary may notarize several blocks at the same height, but it creates at most one share
+/// for each block. Two different shares of the same notary for the same block conflict, e.g.
+/// when only one of them is an acknowledgement or they name different parents.
+fn are_conflicting_notarization_shares(
+    first: &NotarizationShare,
+    second: &NotarizationShare,
+) -> bool {
+    first.signature.signer == second.signature.signer
+        && first.content.notarization_content() == second.content.notarization_content()
+        && first.content != second.content
+}
+
+/// Time after which a missing artifact is requested again, if it was not received
+const ARTIFACT_REQUEST_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Outcome of the validation of an unvalidated artifact.
+enum Validation {
+    Valid,
+    Invalid(InvalidArtifactReason),
+    // the artifact might become valid once the artifacts it depends on are in the pool
+    Pending,
+}
+
 pub struct Validator {
     my_node_id: u8,
+    subnet_params: SubnetParams,
     _schedule: RoundRobin,
+    crypto: Arc<dyn CryptoProvider>,
+    sender_artifact_request: Sender<ConsensusMessageId>,
+    payloads: Arc<RwLock<PayloadStore>>,
+    // time at which each missing artifact was last requested from the peers
+    requested_artifacts: RefCell<HashMap<ConsensusMessageId, Time>>,
 }
 
 impl Validator {
-    pub fn new(my_node_id: u8) -> Self {
+    pub fn new(
+        my_node_id: u8,
+        subnet_params: SubnetParams,
+        crypto: Arc<dyn CryptoProvider>,
+        sender_artifact_request: Sender<ConsensusMessageId>,
+        payloads: Arc<RwLock<PayloadStore>>,
+    ) -> Self {
         Self {
             my_node_id,
+            subnet_params,
             _schedule: RoundRobin::default(),
+            crypto,
+            sender_artifact_request,
+            payloads,
+            requested_artifacts: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Ask the peers for the block proposal of the given block, which another
+    /// artifact depends on, unless it was requested recently. Blocks below the
+    /// finalized height are not needed anymore.
+    fn request_block(&self, pool: &PoolReader<'_>, height: Height, block: &CryptoHashOf<Block>) {
+        let finalized_height = pool.get_finalized_height();
+        if height < finalized_height {
+            return;
+        }
+        let mut requested_artifacts = self.requested_artifacts.borrow_mut();
+        requested_artifacts.retain(|request, _| request.height >= finalized_height);
+        let request = ConsensusMessageId {
+            hash: ConsensusMessageHash::BlockProposal(block.get_ref().clone()),
+            height,
+        };
+        let now = system_time_now();
+        match requested_artifacts.get(&request) {
+            Some(requested_at) if *requested_at + ARTIFACT_REQUEST_TIMEOUT > now => (),
+            _ => {
+                // println!("Requesting missing block proposal: {:?}", request);
+                requested_artifacts.insert(request.clone(), now);
+                // the network layer stops receiving requests only when the replica stops
+                let _ = self.sender_artifact_request.send(request);
+            }
+        }
+    }
+
+    /// Return true if the artifact carries valid signatures of the replicas it
+    /// claims to be signed by. Notarizations and finalizations must be signed
+    /// by a quorum of replicas.
+    fn has_valid_signature(&self, consensus_message: &ConsensusMessage) -> bool {
+        match consensus_message {
+            ConsensusMessage::BlockProposal(proposal) => self
+                .crypto
+                .verify(&to_bytes(&proposal.content), &proposal.signature),
+            ConsensusMessage::NotarizationShare(share) => {
+                let notarization_content = share.content.notarization_content();
+                let is_ack = matches!(&share.content, NotarizationShareContent::COD(content) if content.is_ack);
+                let valid_ack = match share.signature.ack_signature() {
+                    Some(ack_signature) => {
+                        is_ack
+                            && self.crypto.verify(
+                                &to_bytes(&FinalizationContent::new_fp(
+                                    notarization_content.height,
+                                    notarization_content.block.clone(),
+                                )),
+                                &ack_signature,
+                            )
+                    }
+                    None => !is_ack,
+                };
+                valid_ack
+                    && self.crypto.verify(
+                        &to_bytes(&notarization_content),
+                        &share.signature.notarization_signature(),
+                    )
+            }
+            ConsensusMessage::Notarization(notarization) => self.crypto.verify_aggregate(
+                &to_bytes(&notarization.content),
+                &notarization.signature,
+                self.subnet_params.quorum(),
+            ),
+            ConsensusMessage::FinalizationShare(share) => self.crypto.verify(
+                &to_bytes(&FinalizationContent::new(
+                    share.content.height,
+                    share.content.block.clone(),
+                )),
+                &share.signature,
+            ),
+            // FP-finalizations are aggregated from acknowledgements instead of finalization shares
+            ConsensusMessage::Finalization(finalization) => self.crypto.verify_aggregate(
+                &to_bytes(&finalization.content),
+                &finalization.signature,
+                if finalization.content.fp_finalization {
+                    self.subnet_params.fp_finalization_threshold()
+                } else {
+                    self.subnet_params.quorum()
+                },
+            ),
+            ConsensusMessage::RandomBeaconShare(share) => self
+                .crypto
+                .verify_threshold_share(&to_bytes(&share.content), &share.signature),
+            ConsensusMessage::RandomBeacon(beacon) => self
+                .crypto
+                .verify_threshold_signature(&to_bytes(&beacon.content), &beacon.signature),
+            ConsensusMessage::EquivocationEvidence(evidence) => match evidence {
+                EquivocationEvidence::BlockProposal(first, second) => {
+                    self.has_valid_signature(&ConsensusMessage::BlockProposal(first.clone()))
+                        && self
+                            .has_valid_signature(&ConsensusMessage::BlockProposal(second.clone()))
+                }
+                EquivocationEvidence::NotarizationShare(first, second) => {
+                    self.has_valid_signature(&ConsensusMessage::NotarizationShare(first.clone()))
+                        && self.has_valid_signature(&ConsensusMessage::NotarizationShare(
+                            second.clone(),
+                        ))
+                }
+            },
+            ConsensusMessage::CatchUpPackageShare(share) => self
+                .crypto
+                .verify(&to_bytes(&share.content), &share.signature),
+            ConsensusMessage::CatchUpPackage(catch_up_package) => self.crypto.verify_aggregate(
+                &to_bytes(&catch_up_package.content),
+                &catch_up_package.signature,
+                self.subnet_params.quorum(),
+            ),
+            ConsensusMessage::GoodnessArtifact(_) | ConsensusMessage::IMadeABlockArtifact(_) => {
+                true
+            }
+        }
+    }
+
+    /// Return evidence for the unvalidated block proposals and notarization
+    /// shares that conflict with a validated artifact of the same signer, or
+    /// with another unvalidated one, e.g. when both were received in the same
+    /// batch. Must run before the unvalidated artifacts are validated, as
+    /// conflicting shares are then removed from the pool.
+    pub fn detect_equivocations(&self, pool: &PoolReader<'_>) -> Vec<ConsensusMessage> {
+        let unvalidated: Vec<&ConsensusMessage> = pool
+            .pool()
+            .unvalidated()
+            .artifacts
+            .values()
+            .map(|artifact| artifact.as_ref())
+            .collect();
+        let mut evidences = Vec::new();
+        for unvalidated_artifact in unvalidated.iter() {
+            let evidence = match unvalidated_artifact {
+                ConsensusMessage::BlockProposal(proposal) => pool
+                    .pool()
+                    .validated()
+                    .block_proposal()
+                    .get_by_height(proposal.content.value.height)
+                    .find(|other| are_conflicting_proposals(other, proposal))
+                    .or_else(|| {
+                        unvalidated.iter().find_map(|other| match other {
+                            ConsensusMessage::BlockProposal(other)
+                                if are_conflicting_proposals(other, proposal)
+                                    && self.has_valid_signature(
+                                        &ConsensusMessage::BlockProposal(other.clone()),
+                                    ) =>
+                            {
+                                Some(other.clone())
+                            }
+                            _ => None,
+                        })
+                    })
+                    .map(|other| EquivocationEvidence::block_proposals(other, proposal.clone())),
+                ConsensusMessage::NotarizationShare(share) => pool
+                    .get_notarization_shares(share.content.notarization_content().height)
+                    .find(|other| are_conflicting_notarization_shares(other, share))
+                    .or_else(|| {
+                        unvalidated.iter().find_map(|other| match other {
+                            ConsensusMessage::NotarizationShare(other)
+                                if are_conflicting_notarization_shares(other, share)
+                                    && self.has_valid_signature(
+                                        &ConsensusMessage::NotarizationShare(other.clone()),
+                                    ) =>
+                            {
+                                Some(other.clone())
+                            }
+                            _ => None,
+                        })
+                    })
+                    .map(|other| EquivocationEvidence::notarization_shares(other, share.clone())),
+                _ => None,
+            };
+            if let Some(evidence) = evidence {
+                let evidence = ConsensusMessage::EquivocationEvidence(evidence);
+                if self.has_valid_signature(unvalidated_artifact)
+                    && pool
+                        .pool()
+                        .validated()
+                        .get_by_hash(evidence.get_cm_hash().digest())
+                        .is_none()
+                    && !evidences.contains(&evidence)
+                {
+                    // println!("\nDetected equivocation: {:?}", evidence);
+                    evidences.push(evidence);
+                }
+            }
+        }
+        evidences
+    }
+
+    /// Return whether the block proposal extends a notarized block of the
+    /// previous height and was made by a block maker of the right rank.
+    fn validate_block_proposal(
+        &self,
+        pool: &PoolReader<'_>,
+        proposal: &BlockProposal,
+    ) -> Validation {
+        let block = &proposal.content.value;
+        if block.height == 0 {
+            return Validation::Invalid(InvalidArtifactReason::HeightMismatch);
+        }
+        if proposal.content.hash != self.crypto.hash(&to_bytes(block)) {
+            return Validation::Invalid(InvalidArtifactReason::HashMismatch);
         }
+        let parent_height = block.height - 1;
+        let parent = if parent_height == 0 {
+            let genesis = genesis_block();
+            if block.parent != self.crypto.hash(&to_bytes(&genesis)) {
+                return Validation::Invalid(InvalidArtifactReason::ParentMismatch);
+            }
+            genesis
+        } else {
+            let parent_hash: CryptoHashOf<Block> = CryptoHashOf::from(block.parent.clone());
+            match pool.get_notarized_block(&parent_hash, parent_height) {
+                Some(parent) => parent,
+                // the parent or its notarization might not have been received yet
+                None => {
+                    self.request_block(pool, parent_height, &parent_hash);
+                    return Validation::Pending;
+                }
+            }
+        };
+        if block.height != parent.height + 1 {
+            return Validation::Invalid(InvalidArtifactReason::HeightMismatch);
+        }
+        // the ranks at a height are determined by the random beacon of the previous height
+        let beacon = match pool.get_random_beacon(parent_height) {
+            Some(beacon) => beacon,
+            // peers answer requests for a block with the random beacon of its height
+            None => {
+                let parent_hash = CryptoHashOf::from(block.parent.clone());
+                self.request_block(pool, parent_height, &parent_hash);
+                return Validation::Pending;
+            }
+        };
+        let rank = get_block_maker_rank(
+            self.crypto.as_ref(),
+            &beacon,
+            proposal.signature.signer,
+            self.subnet_params.total_nodes_number,
+        );
+        if block.rank != rank {
+            return Validation::Invalid(InvalidArtifactReason::RankMismatch);
+        }
+        // the payload is transferred separately, and its hash was checked when it arrived
+        if !self.payloads.read().unwrap().contains(&block.payload_hash) {
+            // receiving the proposal again starts a new download of its payload if the previous one failed
+            self.request_block(
+                pool,
+                block.height,
+                &CryptoHashOf::from(proposal.content.get_hash().clone()),
+            );
+            return Validation::Pending;
+        }
+        Validation::Valid
+    }
+
+    /// Return whether the share is for a validated block proposal with the
+    /// given parent, if the share states one, and is the only share of its
+    /// signer for that block. Shares of replicas that are not members of the
+    /// subnet never carry a valid signature.
+    #[allow(clippy::too_many_arguments)]
+    fn validate_share(
+        &self,
+        pool: &PoolReader<'_>,
+        signer: u8,
+        height: Height,
+        block: &CryptoHashOf<Block>,
+        parent_hash: Option<&str>,
+        signer_already_shared: bool,
+        accepted_shares: &mut BTreeSet<(u8, CryptoHashOf<Block>)>,
+    ) -> Validation {
+        match pool.get_block(block, height) {
+            Ok(block) => {
+                // acks are grouped by the parent stated in the share, which must be the one of the block
+                if parent_hash.is_some_and(|parent_hash| parent_hash != block.parent) {
+                    return Validation::Invalid(InvalidArtifactReason::ParentMismatch);
+                }
+            }
+            Err(()) => {
+                // the proposal might not have been validated yet, or might have been lost
+                self.request_block(pool, height, block);
+                return Validation::Pending;
+            }
+        }
+        if signer_already_shared || !accepted_shares.insert((signer, block.clone())) {
+            return Validation::Invalid(InvalidArtifactReason::DuplicateShare);
+        }
+        Validation::Valid
+    }
+
+    /// Return whether the block a notarization or finalization refers to is in
+    /// the pool, and request it from the peers otherwise.
+    fn validate_block_reference(
+        &self,
+        pool: &PoolReader<'_>,
+        height: Height,
+        block: &CryptoHashOf<Block>,
+    ) -> Validation {
+        if pool.get_block(block, height).is_ok() {
+            Validation::Valid
+        } else {
+            self.request_block(pool, height, block);
+            Validation::Pending
+        }
+    }
+
+    /// Return whether the finalized block is in the pool and, for an
+    /// FP-finalization, whether the block could be FP-finalized locally.
+    fn validate_finalization(
+        &self,
+        pool: &PoolReader<'_>,
+        finalization: &Finalization,
+    ) -> Validation {
+        let height = finalization.content.height;
+        let block = match pool.get_block(&finalization.content.block, height) {
+            Ok(block) => block,
+            Err(()) => {
+                return self.validate_block_reference(pool, height, &finalization.content.block)
+            }
+        };
+        if finalization.content.fp_finalization
+            && self
+                .subnet_params
+                .fp_finalization_requires_finalized_parent()
+            && !is_parent_finalized(pool, height, &block.parent)
+        {
+            return match pool.get_finalized_block_hash_at_height(height - 1) {
+                Some(_) => Validation::Invalid(InvalidArtifactReason::ParentMismatch),
+                // the parent might be finalized later on, in which case the FP-finalization becomes valid
+                None => Validation::Pending,
+            };
+        }
+        Validation::Valid
+    }
+
+    /// Return whether the share signs the same catch-up package as this
+    /// replica would, and is the only share of its signer at that height.
+    fn validate_catch_up_package_share(
+        &self,
+        pool: &PoolReader<'_>,
+        share: &CatchUpPackageShare,
+        accepted_shares: &mut BTreeSet<(u8, Height)>,
+    ) -> Validation {
+        let height = share.content.height();
+        // the content can only be computed once the height is finalized locally
+        let content = match get_catch_up_content(pool, self.crypto.as_ref(), height) {
+            Some(content) => content,
+            None => return Validation::Pending,
+        };
+        if content != share.content {
+            return Validation::Invalid(InvalidArtifactReason::HashMismatch);
+        }
+        let signer = share.signature.signer;
+        if pool
+            .get_catch_up_package_shares(height)
+            .any(|other| other.signature.signer == signer)
+            || !accepted_shares.insert((signer, height))
+        {
+            return Validation::Invalid(InvalidArtifactReason::DuplicateShare);
+        }
+        Validation::Valid
+    }
+
+    /// Return whether the catch-up package holds a block matching its hash and
+    /// the random beacon of the same height. Its signature proves that the
+    /// block is finalized, so it is valid without the artifacts below it.
+    fn validate_catch_up_package(&self, catch_up_package: &CatchUpPackage) -> Validation {
+        let content = &catch_up_package.content;
+        if content.block.hash != self.crypto.hash(&to_bytes(&content.block.value)) {
+            return Validation::Invalid(InvalidArtifactReason::HashMismatch);
+        }
+        if content.random_beacon.content.height != content.height() {
+            return Validation::Invalid(InvalidArtifactReason::HeightMismatch);
+        }
+        Validation::Valid
     }
 
     pub fn on_state_change(
@@ -33,9 +525,117 @@ impl Validator {
     ) -> (ChangeSet, bool) {
         // println!("\n########## Validator ##########");
         let mut change_set = Vec::new();
+        // shares moved to the validated section in this round, by signer and block
+        let mut accepted_notarization_shares = BTreeSet::new();
+        let mut accepted_finalization_shares = BTreeSet::new();
+        let mut accepted_catch_up_package_shares = BTreeSet::new();
         for unvalidated_artifact in pool_reader.pool().unvalidated().artifacts.values() {
             // println!("Validating artifact {:?}", unvalidated_artifact);
             let consensus_message = unvalidated_artifact.to_owned().into_inner();
+            if pool_reader
+                .pool()
+                .validated()
+                .get_by_hash(consensus_message.get_cm_hash().digest())
+                .is_some()
+            {
+                // the artifact has been received again after it was validated
+                change_set.push(ChangeAction::RemoveFromUnvalidated(consensus_message));
+                continue;
+            }
+            if !self.has_valid_signature(&consensus_message) {
+                change_set.push(ChangeAction::HandleInvalid(
+                    consensus_message,
+                    InvalidArtifactReason::InvalidSignature,
+                ));
+                continue;
+            }
+            let validation = match &consensus_message {
+                ConsensusMessage::BlockProposal(proposal) => {
+                    self.validate_block_proposal(pool_reader, proposal)
+                }
+                ConsensusMessage::NotarizationShare(share) => {
+                    let content = share.content.notarization_content();
+                    let signer = share.signature.signer;
+                    let parent_hash = match &share.content {
+                        NotarizationShareContent::COD(content) => {
+                            Some(content.block_parent_hash.as_str())
+                        }
+                        NotarizationShareContent::ICC(_) => None,
+                    };
+                    self.validate_share(
+                        pool_reader,
+                        signer,
+                        content.height,
+                        &content.block,
+                        parent_hash,
+                        pool_reader
+                            .get_notarization_shares(content.height)
+                            .any(|other| {
+                                other.signature.signer == signer
+                                    && other.content.notarization_content().block == content.block
+                            }),
+                        &mut accepted_notarization_shares,
+                    )
+                }
+                ConsensusMessage::FinalizationShare(share) => {
+                    let signer = share.signature.signer;
+                    self.validate_share(
+                        pool_reader,
+                        signer,
+                        share.content.height,
+                        &share.content.block,
+                        None,
+                        pool_reader
+                            .get_finalization_shares(share.content.height, share.content.height)
+                            .any(|other| {
+                                other.signature.signer == signer
+                                    && other.content.block == share.content.block
+                            }),
+                        &mut accepted_finalization_shares,
+                    )
+                }
+                // the block might be missing after a replica caught up, or might not have been validated yet
+                ConsensusMessage::Notarization(notarization) => self.validate_block_reference(
+                    pool_reader,
+                    notarization.content.height,
+                    &notarization.content.block,
+                ),
+                ConsensusMessage::Finalization(finalization) => {
+                    self.validate_finalization(pool_reader, finalization)
+                }
+                ConsensusMessage::CatchUpPackageShare(share) => self
+                    .validate_catch_up_package_share(
+                        pool_reader,
+                        share,
+                        &mut accepted_catch_up_package_shares,
+                    ),
+                ConsensusMessage::CatchUpPackage(catch_up_package) => {
+                    self.validate_catch_up_package(catch_up_package)
+                }
+                ConsensusMessage::EquivocationEvidence(evidence) => {
+                    if evidence.is_conflicting() {
+                        Validation::Valid
+                    } else {
+                        Validation::Invalid(InvalidArtifactReason::InvalidEvidence)
+                    }
+                }
+                // the goodness of blocks must only be derived from the local pool, so a peer cannot mark a block as good
+                ConsensusMessage::GoodnessArtifact(_)
+                | ConsensusMessage::IMadeABlockArtifact(_) => {
+                    Validation::Invalid(InvalidArtifactReason::LocalArtifact)
+                }
+                _ => Validation::Valid,
+            };
+            match validation {
+                Validation::Valid => (),
+                // invalid artifacts are removed from the unvalidated section and never validated
+                Validation::Invalid(reason) => {
+                    change_set.push(ChangeAction::HandleInvalid(consensus_message, reason));
+                    continue;
+                }
+                // pending artifacts are validated again once their dependencies are in the pool
+                Validation::Pending => continue,
+            }
             if let ConsensusMessage::Finalization(finalization) = &consensus_message {
                 // only insert finalization of type DK if received by peer before it was finalized locally
                 if !finalization_times
@@ -7,13 +7,16 @@ use super::{
     consensus_subcomponents::{
         aggregator::{Finalization, Notarization},
         block_maker::BlockProposal,
+        catch_up_package_maker::{CatchUpPackage, CatchUpPackageShare},
         finalizer::FinalizationShare,
         goodifier::{GoodnessArtifact, IMadeABlockArtifact},
         notary::{NotarizationShare, NotarizationShareContent},
+        random_beacon_maker::{RandomBeacon, RandomBeaconShare},
+        validator::EquivocationEvidence,
     },
 };
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct HeightIndex<T: Eq + Debug> {
     buckets: BTreeMap<u64, Vec<T>>,
 }
@@ -79,6 +82,7 @@ impl<T: Eq + Clone + Debug> HeightIndex<T> {
     }
 }
 
+#[derive(Debug, PartialEq)]
 pub struct Indexes {
     pub block_proposal: HeightIndex<CryptoHashOf<BlockProposal>>,
     pub notarization_share: HeightIndex<CryptoHashOf<NotarizationShare>>,
@@ -87,6 +91,11 @@ pub struct Indexes {
     pub finalization: HeightIndex<CryptoHashOf<Finalization>>,
     pub goodness_artifact: HeightIndex<CryptoHashOf<GoodnessArtifact>>,
     pub i_made_a_block_artifact: HeightIndex<CryptoHashOf<IMadeABlockArtifact>>,
+    pub random_beacon_share: HeightIndex<CryptoHashOf<RandomBeaconShare>>,
+    pub random_beacon: HeightIndex<CryptoHashOf<RandomBeacon>>,
+    pub equivocation_evidence: HeightIndex<CryptoHashOf<EquivocationEvidence>>,
+    pub catch_up_package_share: HeightIndex<CryptoHashOf<CatchUpPackageShare>>,
+    pub catch_up_package: HeightIndex<CryptoHashOf<CatchUpPackage>>,
 }
 
 #[allow(clippy::new_without_default)]
@@ -100,6 +109,11 @@ impl Indexes {
             finalization: HeightIndex::new(),
             goodness_artifact: HeightIndex::new(),
             i_made_a_block_artifact: HeightIndex::new(),
+            random_beacon_share: HeightIndex::new(),
+            random_beacon: HeightIndex::new(),
+            equivocation_evidence: HeightIndex::new(),
+            catch_up_package_share: HeightIndex::new(),
+            catch_up_package: HeightIndex::new(),
         }
     }
 
@@ -131,6 +145,21 @@ impl Indexes {
             ConsensusMessage::IMadeABlockArtifact(artifact) => self
                 .i_made_a_block_artifact
                 .insert(artifact.block_height, &CryptoHashOf::from(hash)),
+            ConsensusMessage::RandomBeaconShare(artifact) => self
+                .random_beacon_share
+                .insert(artifact.content.height, &CryptoHashOf::from(hash)),
+            ConsensusMessage::RandomBeacon(artifact) => self
+                .random_beacon
+                .insert(artifact.content.height, &CryptoHashOf::from(hash)),
+            ConsensusMessage::EquivocationEvidence(artifact) => self
+                .equivocation_evidence
+                .insert(artifact.height(), &CryptoHashOf::from(hash)),
+            ConsensusMessage::CatchUpPackageShare(artifact) => self
+                .catch_up_package_share
+                .insert(artifact.content.height(), &CryptoHashOf::from(hash)),
+            ConsensusMessage::CatchUpPackage(artifact) => self
+                .catch_up_package
+                .insert(artifact.content.height(), &CryptoHashOf::from(hash)),
         };
     }
 
@@ -162,6 +191,21 @@ impl Indexes {
             ConsensusMessage::IMadeABlockArtifact(artifact) => self
                 .i_made_a_block_artifact
                 .remove(artifact.block_height, &CryptoHashOf::from(hash)),
+            ConsensusMessage::RandomBeaconShare(artifact) => self
+                .random_beacon_share
+                .remove(artifact.content.height, &CryptoHashOf::from(hash)),
+            ConsensusMessage::RandomBeacon(artifact) => self
+                .random_beacon
+                .remove(artifact.content.height, &CryptoHashOf::from(hash)),
+            ConsensusMessage::EquivocationEvidence(artifact) => self
+                .equivocation_evidence
+                .remove(artifact.height(), &CryptoHashOf::from(hash)),
+            ConsensusMessage::CatchUpPackageShare(artifact) => self
+                .catch_up_package_share
+                .remove(artifact.content.height(), &CryptoHashOf::from(hash)),
+            ConsensusMessage::CatchUpPackage(artifact) => self
+                .catch_up_package
+                .remove(artifact.content.height(), &CryptoHashOf::from(hash)),
         };
     }
 }
@@ -242,6 +286,36 @@ impl SelectIndex for CryptoHashOf<IMadeABlockArtifact> {
     }
 }
 
+impl SelectIndex for CryptoHashOf<RandomBeaconShare> {
+    fn select_index(indexes: &Indexes) -> &HeightIndex<Self> {
+        &indexes.random_beacon_share
+    }
+}
+
+impl SelectIndex for CryptoHashOf<RandomBeacon> {
+    fn select_index(indexes: &Indexes) -> &HeightIndex<Self> {
+        &indexes.random_beacon
+    }
+}
+
+impl SelectIndex for CryptoHashOf<EquivocationEvidence> {
+    fn select_index(indexes: &Indexes) -> &HeightIndex<Self> {
+        &indexes.equivocation_evidence
+    }
+}
+
+impl SelectIndex for CryptoHashOf<CatchUpPackageShare> {
+    fn select_index(indexes: &Indexes) -> &HeightIndex<Self> {
+        &indexes.catch_up_package_share
+    }
+}
+
+impl SelectIndex for CryptoHashOf<CatchUpPackage> {
+    fn select_index(indexes: &Indexes) -> &HeightIndex<Self> {
+        &indexes.catch_up_package
+    }
+}
+
 pub struct HeightRange {
     pub min: Height,
     pub max: Height,
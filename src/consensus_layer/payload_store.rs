@@ -0,0 +1,230 @@
+//! Storage of the payloads of the block proposals. Blocks only hold the hash of
+//! their payload, so that proposals stay small enough to be gossiped, while the
+//! payloads are transferred in chunks between peers on request. A proposal is
+//! validated once its payload is in the store. When the pool is persisted, each
+//! payload is also written to a file, so that a restarted replica can still
+//! serve the payloads of the proposals it reloads.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::CryptoHash;
+
+use super::{consensus_subcomponents::block_maker::Payload, height_index::Height};
+
+/// Number of bytes of a payload transferred in each chunk
+pub const PAYLOAD_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Return an upper bound on the number of chunks of the payloads of blocks of
+/// the given size, above which the chunks sent by a peer are refused.
+pub fn max_chunk_count(blocksize: usize) -> usize {
+    blocksize / PAYLOAD_CHUNK_SIZE + 1
+}
+
+/// A chunk of a payload, along with the number of chunks of the payload, so
+/// that the peer downloading it knows which chunks to request next.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PayloadChunk {
+    pub index: usize,
+    pub count: usize,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct PayloadStore {
+    // payloads by hash, along with the height of the block holding them
+    payloads: BTreeMap<CryptoHash, (Height, Payload)>,
+    // directory holding a file for each payload, if the payloads are persisted
+    dir: Option<PathBuf>,
+}
+
+impl PayloadStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open the store persisted in the given directory, or create an empty one
+    /// if the directory does not exist yet.
+    pub fn open(dir: &Path) -> Self {
+        fs::create_dir_all(dir).expect("can create payload directory");
+        let mut payloads = BTreeMap::new();
+        for entry in fs::read_dir(dir).expect("can read payload directory") {
+            let path = entry.expect("can read payload directory").path();
+            match parse_payload_file_name(&path) {
+                Some((height, hash)) => {
+                    let bytes = fs::read(&path).expect("can read payload file");
+                    payloads.insert(hash, (height, Payload::from_bytes(bytes)));
+                }
+                // a payload which was only partially written when the process died is discarded
+                None => fs::remove_file(&path).expect("can remove payload file"),
+            }
+        }
+        println!("Loaded {} payloads from {:?}", payloads.len(), dir);
+        Self {
+            payloads,
+            dir: Some(dir.to_path_buf()),
+        }
+    }
+
+    fn path(&self, hash: &CryptoHash, height: Height) -> Option<PathBuf> {
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}_{}.payload", height, hash)))
+    }
+
+    pub fn insert(&mut self, hash: CryptoHash, height: Height, payload: Payload) {
+        if self.payloads.contains_key(&hash) {
+            return;
+        }
+        if let Some(path) = self.path(&hash, height) {
+            // the file only gets its final name once complete
+            let partial_path = path.with_extension("partial");
+            fs::write(&partial_path, payload.as_bytes()).expect("can write payload file");
+            fs::rename(&partial_path, &path).expect("can write payload file");
+        }
+        self.payloads.insert(hash, (height, payload));
+    }
+
+    pub fn contains(&self, hash: &CryptoHash) -> bool {
+        self.payloads.contains_key(hash)
+    }
+
+    pub fn remove(&mut self, hash: &CryptoHash) {
+        if let Some((height, _)) = self.payloads.remove(hash) {
+            if let Some(path) = self.path(hash, height) {
+                fs::remove_file(path).expect("can remove payload file");
+            }
+        }
+    }
+
+    /// Remove the payloads of the blocks below the given height.
+    pub fn remove_below(&mut self, height: Height) {
+        let hashes: Vec<CryptoHash> = self
+            .payloads
+            .iter()
+            .filter(|(_, (payload_height, _))| *payload_height < height)
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        for hash in hashes {
+            self.remove(&hash);
+        }
+    }
+
+    /// Return the chunk with the given index of the payload with the given
+    /// hash, if the payload is in the store.
+    pub fn get_chunk(&self, hash: &CryptoHash, index: usize) -> Option<PayloadChunk> {
+        let (_, payload) = self.payloads.get(hash)?;
+        let bytes = payload.as_bytes();
+        // an empty payload is transferred as a single empty chunk
+        let count = bytes.len().div_ceil(PAYLOAD_CHUNK_SIZE).max(1);
+        if index >= count {
+            return None;
+        }
+        let start = index * PAYLOAD_CHUNK_SIZE;
+        let end = (start + PAYLOAD_CHUNK_SIZE).min(bytes.len());
+        Some(PayloadChunk {
+            index,
+            count,
+            data: bytes[start..end].to_vec(),
+        })
+    }
+}
+
+/// Return the height and the hash of the payload stored in the file at the
+/// given path, which is named `<height>_<hash>.payload`.
+fn parse_payload_file_name(path: &Path) -> Option<(Height, CryptoHash)> {
+    let name = path.file_name()?.to_str()?.strip_suffix(".payload")?;
+    let (height, hash) = name.split_once('_')?;
+    Some((height.parse().ok()?, hash.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_with_payload(size: usize) -> (PayloadStore, CryptoHash, Vec<u8>) {
+        let mut store = PayloadStore::new();
+        let payload = Payload::new(size);
+        let bytes = payload.as_bytes().to_vec();
+        let hash = String::from("payload");
+        store.insert(hash.clone(), 1, payload);
+        (store, hash, bytes)
+    }
+
+    fn chunks(store: &PayloadStore, hash: &CryptoHash) -> Vec<PayloadChunk> {
+        let count = store.get_chunk(hash, 0).unwrap().count;
+        (0..count)
+            .map(|index| store.get_chunk(hash, index).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn chunks_rebuild_the_payload() {
+        for size in [
+            1,
+            PAYLOAD_CHUNK_SIZE - 1,
+            PAYLOAD_CHUNK_SIZE,
+            PAYLOAD_CHUNK_SIZE + 1,
+            3 * PAYLOAD_CHUNK_SIZE + 7,
+        ] {
+            let (store, hash, bytes) = store_with_payload(size);
+            let chunks = chunks(&store, &hash);
+            assert_eq!(chunks.len(), size.div_ceil(PAYLOAD_CHUNK_SIZE));
+            for (index, chunk) in chunks.iter().enumerate() {
+                assert_eq!(chunk.index, index);
+                assert_eq!(chunk.count, chunks.len());
+            }
+            // only the last chunk may be partial
+            let (last, full) = chunks.split_last().unwrap();
+            assert!(full
+                .iter()
+                .all(|chunk| chunk.data.len() == PAYLOAD_CHUNK_SIZE));
+            assert_eq!(last.data.len(), size - full.len() * PAYLOAD_CHUNK_SIZE);
+            let rebuilt: Vec<u8> = chunks.into_iter().flat_map(|chunk| chunk.data).collect();
+            assert_eq!(rebuilt, bytes);
+        }
+    }
+
+    #[test]
+    fn empty_payload_is_a_single_empty_chunk() {
+        let (store, hash, _) = store_with_payload(0);
+        let chunk = store.get_chunk(&hash, 0).unwrap();
+        assert_eq!((chunk.index, chunk.count), (0, 1));
+        assert!(chunk.data.is_empty());
+        assert!(store.get_chunk(&hash, 1).is_none());
+    }
+
+    #[test]
+    fn no_chunk_past_the_end_or_of_an_unknown_payload() {
+        let (store, hash, _) = store_with_payload(2 * PAYLOAD_CHUNK_SIZE + 1);
+        assert!(store.get_chunk(&hash, 2).is_some());
+        assert!(store.get_chunk(&hash, 3).is_none());
+        assert!(store.get_chunk(&hash, usize::MAX).is_none());
+        assert!(store.get_chunk(&String::from("unknown"), 0).is_none());
+    }
+
+    #[test]
+    fn chunk_count_is_within_the_bound_of_the_blocksize() {
+        for blocksize in [
+            0,
+            1,
+            PAYLOAD_CHUNK_SIZE - 1,
+            PAYLOAD_CHUNK_SIZE,
+            PAYLOAD_CHUNK_SIZE + 1,
+            4 * PAYLOAD_CHUNK_SIZE,
+        ] {
+            let (store, hash, _) = store_with_payload(blocksize);
+            let count = store.get_chunk(&hash, 0).unwrap().count;
+            assert!(count <= max_chunk_count(blocksize));
+        }
+        // a peer claiming more chunks than a block can hold is refused
+        assert_eq!(max_chunk_count(PAYLOAD_CHUNK_SIZE + 1), 2);
+        assert_eq!(max_chunk_count(4 * PAYLOAD_CHUNK_SIZE + 1), 5);
+    }
+}
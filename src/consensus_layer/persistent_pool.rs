@@ -0,0 +1,297 @@
+//! Disk-backed storage of a pool section. Every change to the section is
+//! appended to a log file, in which each line is a JSON encoded [LogEntry]. The
+//! artifacts are also kept in memory, so that queries do not read the log,
+//! which is only read to recover the section when a replica restarts.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::crypto::CryptoHash;
+
+use super::{
+    artifacts::{ConsensusMessage, ConsensusMessageHashable, HasTimestamp, IntoInner},
+    height_index::Indexes,
+    pool::{MutablePoolSection, PoolSection, PoolSectionOp, PoolSectionOps, PoolSectionStorage},
+};
+
+#[derive(Serialize, Deserialize)]
+enum LogEntry<T> {
+    Insert(T),
+    Remove(CryptoHash),
+}
+
+pub struct PersistentPoolSection<T> {
+    log: File,
+    artifacts: BTreeMap<CryptoHash, T>,
+    indexes: Indexes,
+}
+
+impl<T> PersistentPoolSection<T>
+where
+    T: IntoInner<ConsensusMessage> + HasTimestamp + Clone + Debug + Serialize + DeserializeOwned,
+{
+    /// Open the pool section stored in the log at the given path, or create an
+    /// empty one if there is no log yet. The log is compacted, so that it only
+    /// holds the artifacts which have not been removed from the section.
+    pub fn open(path: &Path) -> Self {
+        let artifacts = read_log::<T>(path);
+        println!("Loaded {} artifacts from pool log {:?}", artifacts.len(), path);
+        // the compacted log is written next to the previous one, which is only replaced once complete
+        let compacted_path = PathBuf::from(format!("{}.compacted", path.display()));
+        let mut section = Self {
+            log: OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&compacted_path)
+                .expect("can create pool log"),
+            artifacts: BTreeMap::new(),
+            indexes: Indexes::new(),
+        };
+        for artifact in artifacts.into_values() {
+            section.insert(artifact);
+        }
+        fs::rename(&compacted_path, path).expect("can replace pool log");
+        section
+    }
+
+    fn append(&mut self, entry: &LogEntry<&T>) {
+        let mut line = serde_json::to_vec(entry).expect("can serialize pool log entry");
+        line.push(b'\n');
+        // the entry is handed to the operating system right away, so that it is not lost if the process dies
+        self.log.write_all(&line).expect("can append to pool log");
+    }
+
+    fn insert(&mut self, artifact: T) {
+        let msg = artifact.as_ref();
+        let hash = msg.get_cm_hash().digest().clone();
+        if self.artifacts.contains_key(&hash) {
+            return;
+        }
+        self.append(&LogEntry::Insert(&artifact));
+        self.indexes.insert(artifact.as_ref(), hash.clone());
+        self.artifacts.insert(hash, artifact);
+    }
+
+    fn remove(&mut self, hash: &CryptoHash) {
+        if let Some(artifact) = self.artifacts.remove(hash) {
+            self.append(&LogEntry::Remove(hash.clone()));
+            self.indexes.remove(artifact.as_ref(), hash.clone());
+        }
+    }
+}
+
+impl<T> PoolSectionStorage for PersistentPoolSection<T>
+where
+    T: IntoInner<ConsensusMessage> + HasTimestamp + Clone + Debug + Serialize + DeserializeOwned,
+{
+    type Artifact = T;
+
+    fn indexes(&self) -> &Indexes {
+        &self.indexes
+    }
+
+    fn get_artifact(&self, hash: &CryptoHash) -> Option<T> {
+        self.artifacts.get(hash).cloned()
+    }
+}
+
+impl<T> MutablePoolSection<T> for PersistentPoolSection<T>
+where
+    T: IntoInner<ConsensusMessage> + HasTimestamp + Clone + Debug + Serialize + DeserializeOwned,
+{
+    fn mutate(&mut self, ops: PoolSectionOps<T>) {
+        for op in ops.ops {
+            match op {
+                PoolSectionOp::Insert(artifact) => self.insert(artifact),
+                PoolSectionOp::Remove(msg_id) => self.remove(msg_id.hash.digest()),
+            }
+        }
+    }
+
+    fn pool_section(&self) -> &dyn PoolSection<T> {
+        self
+    }
+}
+
+/// Return the artifacts of the log at the given path which have not been
+/// removed. An entry which was only partially written when the process died
+/// ends the log.
+fn read_log<T>(path: &Path) -> BTreeMap<CryptoHash, T>
+where
+    T: IntoInner<ConsensusMessage> + DeserializeOwned,
+{
+    let mut artifacts = BTreeMap::new();
+    let log = match File::open(path) {
+        Ok(log) => log,
+        Err(_) => return artifacts,
+    };
+    for line in BufReader::new(log).lines() {
+        let entry = match line.map(|line| serde_json::from_str::<LogEntry<T>>(&line)) {
+            Ok(Ok(entry)) => entry,
+            _ => {
+                println!(
+                    "Ignoring incomplete entry at the end of pool log {:?}",
+                    path
+                );
+                break;
+            }
+        };
+        match entry {
+            LogEntry::Insert(artifact) => {
+                artifacts.insert(artifact.as_ref().get_cm_hash().digest().clone(), artifact);
+            }
+            LogEntry::Remove(hash) => {
+                artifacts.remove(&hash);
+            }
+        }
+    }
+    artifacts
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::{
+        consensus_layer::{
+            artifacts::ValidatedArtifact,
+            consensus_subcomponents::random_beacon_maker::{
+                RandomBeaconContent, RandomBeaconShare,
+            },
+        },
+        crypto::{CryptoHashOf, ThresholdSignatureShare},
+        time_source::Time,
+    };
+
+    type Section = PersistentPoolSection<ValidatedArtifact<ConsensusMessage>>;
+
+    // a single artifact per height, so that the order of the index buckets does not depend on the order of insertion
+    fn artifact(height: u64) -> ValidatedArtifact<ConsensusMessage> {
+        ValidatedArtifact {
+            msg: ConsensusMessage::RandomBeaconShare(RandomBeaconShare {
+                content: RandomBeaconContent::new(height, CryptoHashOf::from(String::new())),
+                signature: ThresholdSignatureShare {
+                    signer: 1,
+                    signature: vec![height as u8],
+                },
+            }),
+            timestamp: Time(height),
+        }
+    }
+
+    fn hash(artifact: &ValidatedArtifact<ConsensusMessage>) -> CryptoHash {
+        artifact.msg.get_cm_hash().digest().clone()
+    }
+
+    fn mutate(section: &mut Section, inserted: &[u64], removed: &[u64]) {
+        let mut ops = PoolSectionOps::new();
+        for height in inserted {
+            ops.insert(artifact(*height));
+        }
+        for height in removed {
+            ops.remove(artifact(*height).msg.get_id());
+        }
+        section.mutate(ops);
+    }
+
+    fn log_entries(path: &Path) -> usize {
+        BufReader::new(File::open(path).unwrap()).lines().count()
+    }
+
+    fn assert_same_section(reopened: &Section, section: &Section) {
+        assert_eq!(reopened.indexes(), section.indexes());
+        assert_eq!(
+            reopened.artifacts.keys().collect::<BTreeSet<_>>(),
+            section.artifacts.keys().collect::<BTreeSet<_>>()
+        );
+        for (hash, artifact) in section.artifacts.iter() {
+            assert_eq!(reopened.get_artifact(hash).as_ref(), Some(artifact));
+        }
+    }
+
+    #[test]
+    fn reopen_replays_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("validated.log");
+        let mut section = Section::open(&path);
+        mutate(&mut section, &[1, 2, 3], &[]);
+        // inserting an artifact which is already in the section does not log it again
+        mutate(&mut section, &[2], &[]);
+        assert_eq!(log_entries(&path), 3);
+
+        let reopened = Section::open(&path);
+        assert_eq!(reopened.artifacts.len(), 3);
+        assert_same_section(&reopened, &section);
+    }
+
+    #[test]
+    fn reopen_after_removals() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("validated.log");
+        let mut section = Section::open(&path);
+        mutate(&mut section, &[1, 2, 3, 4], &[]);
+        mutate(&mut section, &[5], &[2, 4]);
+        // removing an artifact which is not in the section does not log anything
+        mutate(&mut section, &[], &[6]);
+        assert_eq!(log_entries(&path), 7);
+
+        let reopened = Section::open(&path);
+        assert_same_section(&reopened, &section);
+        assert!(reopened.get_artifact(&hash(&artifact(2))).is_none());
+        assert!(reopened.get_artifact(&hash(&artifact(4))).is_none());
+        assert_eq!(
+            reopened
+                .indexes()
+                .random_beacon_share
+                .heights()
+                .collect::<Vec<_>>(),
+            vec![&1, &3, &5]
+        );
+    }
+
+    #[test]
+    fn open_compacts_the_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("validated.log");
+        let mut section = Section::open(&path);
+        mutate(&mut section, &[1, 2, 3], &[1, 3]);
+        assert_eq!(log_entries(&path), 5);
+        drop(section);
+
+        // only the insertion of the remaining artifact is kept
+        let mut reopened = Section::open(&path);
+        assert_eq!(log_entries(&path), 1);
+        assert!(!PathBuf::from(format!("{}.compacted", path.display())).exists());
+
+        // the compacted log keeps recording the changes made after the section is reopened
+        mutate(&mut reopened, &[4], &[2]);
+        assert_eq!(log_entries(&path), 3);
+        let reopened_again = Section::open(&path);
+        assert_same_section(&reopened_again, &reopened);
+        assert_eq!(log_entries(&path), 1);
+    }
+
+    #[test]
+    fn open_ignores_an_incomplete_last_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("validated.log");
+        let mut section = Section::open(&path);
+        mutate(&mut section, &[1, 2], &[]);
+        drop(section);
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        log.write_all(b"{\"Insert\":{\"msg\"").unwrap();
+
+        let reopened = Section::open(&path);
+        assert_eq!(reopened.artifacts.len(), 2);
+        assert_eq!(log_entries(&path), 2);
+    }
+}
@@ -1,4 +1,8 @@
-use std::{collections::BTreeMap, fmt::Debug};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    sync::{Arc, RwLock},
+};
 
 use crate::{
     crypto::{CryptoHash, CryptoHashOf},
@@ -8,20 +12,108 @@ use crate::{
 use super::{
     artifacts::{
         ChangeAction, ChangeSet, ConsensusMessage, ConsensusMessageHashable, ConsensusMessageId,
-        HasTimestamp, IntoInner, UnvalidatedArtifact, ValidatedArtifact,
+        HasTimestamp, IntoInner, InvalidArtifactCounts, InvalidArtifactReason, UnvalidatedArtifact,
+        ValidatedArtifact, UNKNOWN_SENDER,
     },
     consensus_subcomponents::{
         aggregator::{Finalization, Notarization},
         block_maker::{Block, BlockProposal},
+        catch_up_package_maker::{CatchUpPackage, CatchUpPackageShare},
         finalizer::FinalizationShare,
         goodifier::{GoodnessArtifact, IMadeABlockArtifact},
         notary::NotarizationShare,
+        random_beacon_maker::{RandomBeacon, RandomBeaconShare},
+        validator::EquivocationEvidence,
     },
-    height_index::{Height, HeightIndex, HeightIndexedPool, HeightRange, Indexes, SelectIndex},
+    height_index::{Height, HeightIndexedPool, HeightRange, Indexes, SelectIndex},
 };
 
-type UnvalidatedConsensusArtifact = UnvalidatedArtifact<ConsensusMessage>;
-type ValidatedConsensusArtifact = ValidatedArtifact<ConsensusMessage>;
+pub type UnvalidatedConsensusArtifact = UnvalidatedArtifact<ConsensusMessage>;
+pub type ValidatedConsensusArtifact = ValidatedArtifact<ConsensusMessage>;
+
+/// Storage of the artifacts of a pool section, which are looked up by hash and
+/// indexed by height for each type of artifact.
+pub trait PoolSectionStorage {
+    type Artifact: IntoInner<ConsensusMessage> + HasTimestamp;
+
+    fn indexes(&self) -> &Indexes;
+
+    /// Get an artifact by its hash
+    fn get_artifact(&self, hash: &CryptoHash) -> Option<Self::Artifact>;
+}
+
+/// Read access to a section of the consensus pool, independently of how its
+/// artifacts are stored.
+pub trait PoolSection<T> {
+    /// Get a consensus message by its hash
+    fn get_by_hash(&self, hash: &CryptoHash) -> Option<T>;
+    fn get_timestamp(&self, msg_id: &ConsensusMessageId) -> Option<Time>;
+    fn notarization_share(&self) -> &dyn HeightIndexedPool<NotarizationShare>;
+    fn notarization(&self) -> &dyn HeightIndexedPool<Notarization>;
+    fn block_proposal(&self) -> &dyn HeightIndexedPool<BlockProposal>;
+    fn finalization_share(&self) -> &dyn HeightIndexedPool<FinalizationShare>;
+    fn finalization(&self) -> &dyn HeightIndexedPool<Finalization>;
+    fn goodness_artifact(&self) -> &dyn HeightIndexedPool<GoodnessArtifact>;
+    fn i_made_a_block_artifact(&self) -> &dyn HeightIndexedPool<IMadeABlockArtifact>;
+    fn random_beacon_share(&self) -> &dyn HeightIndexedPool<RandomBeaconShare>;
+    fn random_beacon(&self) -> &dyn HeightIndexedPool<RandomBeacon>;
+    fn equivocation_evidence(&self) -> &dyn HeightIndexedPool<EquivocationEvidence>;
+    fn catch_up_package_share(&self) -> &dyn HeightIndexedPool<CatchUpPackageShare>;
+    fn catch_up_package(&self) -> &dyn HeightIndexedPool<CatchUpPackage>;
+}
+
+pub trait MutablePoolSection<T>: PoolSection<T> {
+    fn mutate(&mut self, ops: PoolSectionOps<T>);
+    fn pool_section(&self) -> &dyn PoolSection<T>;
+}
+
+impl<S: PoolSectionStorage> PoolSection<S::Artifact> for S {
+    fn get_by_hash(&self, hash: &CryptoHash) -> Option<S::Artifact> {
+        self.get_artifact(hash)
+    }
+
+    fn get_timestamp(&self, msg_id: &ConsensusMessageId) -> Option<Time> {
+        self.get_artifact(msg_id.hash.digest())
+            .map(|x| x.timestamp())
+    }
+
+    fn notarization_share(&self) -> &dyn HeightIndexedPool<NotarizationShare> {
+        self
+    }
+    fn notarization(&self) -> &dyn HeightIndexedPool<Notarization> {
+        self
+    }
+    fn block_proposal(&self) -> &dyn HeightIndexedPool<BlockProposal> {
+        self
+    }
+    fn finalization_share(&self) -> &dyn HeightIndexedPool<FinalizationShare> {
+        self
+    }
+    fn finalization(&self) -> &dyn HeightIndexedPool<Finalization> {
+        self
+    }
+    fn goodness_artifact(&self) -> &dyn HeightIndexedPool<GoodnessArtifact> {
+        self
+    }
+    fn i_made_a_block_artifact(&self) -> &dyn HeightIndexedPool<IMadeABlockArtifact> {
+        self
+    }
+    fn random_beacon_share(&self) -> &dyn HeightIndexedPool<RandomBeaconShare> {
+        self
+    }
+    fn random_beacon(&self) -> &dyn HeightIndexedPool<RandomBeacon> {
+        self
+    }
+    fn equivocation_evidence(&self) -> &dyn HeightIndexedPool<EquivocationEvidence> {
+        self
+    }
+    fn catch_up_package_share(&self) -> &dyn HeightIndexedPool<CatchUpPackageShare> {
+        self
+    }
+    fn catch_up_package(&self) -> &dyn HeightIndexedPool<CatchUpPackage> {
+        self
+    }
+}
 
 pub struct InMemoryPoolSection<T: IntoInner<ConsensusMessage>> {
     pub artifacts: BTreeMap<CryptoHash, T>,
@@ -44,30 +136,6 @@ impl<T: IntoInner<ConsensusMessage> + HasTimestamp + Clone + Debug> InMemoryPool
         }
     }
 
-    fn pool_section(&self) -> &InMemoryPoolSection<T> {
-        self
-    }
-
-    fn mutate(&mut self, ops: PoolSectionOps<T>) {
-        for op in ops.ops {
-            match op {
-                PoolSectionOp::Insert(artifact) => {
-                    // println!("Inserting artifact: {:?}", artifact);
-                    self.insert(artifact);
-                }
-                PoolSectionOp::Remove(msg_id) => {
-                    //if
-                    self.remove(&msg_id);
-                    //.is_none() {
-                    // println!("Error removing artifact {:?}", &msg_id);
-                    //} else {
-                    // println!("Removing artifact");
-                    //}
-                }
-            }
-        }
-    }
-
     fn insert(&mut self, artifact: T) {
         let msg = artifact.as_ref();
         let hash = msg.get_cm_hash().digest().clone();
@@ -79,27 +147,6 @@ impl<T: IntoInner<ConsensusMessage> + HasTimestamp + Clone + Debug> InMemoryPool
         self.remove_by_hash(msg_id.hash.digest())
     }
 
-    fn get_by_hashes<S: ConsensusMessageHashable>(&self, hashes: Vec<&CryptoHashOf<S>>) -> Vec<S> {
-        hashes
-            .iter()
-            .map(|hash| {
-                let artifact_opt = self.get_by_hash(hash.get_ref());
-                match artifact_opt {
-                    Some(artifact) => match S::assert(artifact.as_ref()) {
-                        Some(value) => value.clone(),
-                        _ => panic!("Unexpected message type"),
-                    },
-                    _ => panic!("Can't find artifact with hash: {:?}", hash.get_ref()),
-                }
-            })
-            .collect()
-    }
-
-    /// Get a consensus message by its hash
-    pub fn get_by_hash(&self, hash: &CryptoHash) -> Option<T> {
-        self.artifacts.get(hash).cloned()
-    }
-
     /// Remove a consensus message by its hash
     pub fn remove_by_hash(&mut self, hash: &CryptoHash) -> Option<T> {
         self.artifacts.remove(hash).map(|artifact| {
@@ -107,53 +154,80 @@ impl<T: IntoInner<ConsensusMessage> + HasTimestamp + Clone + Debug> InMemoryPool
             artifact
         })
     }
+}
 
-    fn select_index<S: SelectIndex>(&self) -> &HeightIndex<S> {
-        SelectIndex::select_index(&self.indexes)
-    }
+impl<T: IntoInner<ConsensusMessage> + HasTimestamp + Clone + Debug> PoolSectionStorage
+    for InMemoryPoolSection<T>
+{
+    type Artifact = T;
 
-    pub fn get_timestamp(&self, msg_id: &ConsensusMessageId) -> Option<Time> {
-        self.get_by_hash(msg_id.hash.digest())
-            .map(|x| x.timestamp())
+    fn indexes(&self) -> &Indexes {
+        &self.indexes
     }
 
-    pub fn notarization_share(&self) -> &dyn HeightIndexedPool<NotarizationShare> {
-        self
+    fn get_artifact(&self, hash: &CryptoHash) -> Option<T> {
+        self.artifacts.get(hash).cloned()
     }
+}
 
-    pub fn notarization(&self) -> &dyn HeightIndexedPool<Notarization> {
-        self
+impl<T: IntoInner<ConsensusMessage> + HasTimestamp + Clone + Debug> MutablePoolSection<T>
+    for InMemoryPoolSection<T>
+{
+    fn mutate(&mut self, ops: PoolSectionOps<T>) {
+        for op in ops.ops {
+            match op {
+                PoolSectionOp::Insert(artifact) => {
+                    // println!("Inserting artifact: {:?}", artifact);
+                    self.insert(artifact);
+                }
+                PoolSectionOp::Remove(msg_id) => {
+                    //if
+                    self.remove(&msg_id);
+                    //.is_none() {
+                    // println!("Error removing artifact {:?}", &msg_id);
+                    //} else {
+                    // println!("Removing artifact");
+                    //}
+                }
+            }
+        }
     }
 
-    pub fn block_proposal(&self) -> &dyn HeightIndexedPool<BlockProposal> {
+    fn pool_section(&self) -> &dyn PoolSection<T> {
         self
     }
+}
 
-    pub fn finalization_share(&self) -> &dyn HeightIndexedPool<FinalizationShare> {
-        self
-    }
-    fn finalization(&self) -> &dyn HeightIndexedPool<Finalization> {
-        self
-    }
-    pub fn goodness_artifact(&self) -> &dyn HeightIndexedPool<GoodnessArtifact> {
-        self
-    }
-    pub fn i_made_a_block_artifact(&self) -> &dyn HeightIndexedPool<IMadeABlockArtifact> {
-        self
-    }
+fn get_by_hashes<S: ConsensusMessageHashable, P: PoolSectionStorage>(
+    section: &P,
+    hashes: Vec<&CryptoHashOf<S>>,
+) -> Vec<S> {
+    hashes
+        .iter()
+        .map(|hash| {
+            let artifact_opt = section.get_artifact(hash.get_ref());
+            match artifact_opt {
+                Some(artifact) => match S::assert(artifact.as_ref()) {
+                    Some(value) => value.clone(),
+                    _ => panic!("Unexpected message type"),
+                },
+                _ => panic!("Can't find artifact with hash: {:?}", hash.get_ref()),
+            }
+        })
+        .collect()
 }
 
-impl<
-        T: ConsensusMessageHashable + 'static + Debug,
-        S: IntoInner<ConsensusMessage> + HasTimestamp + Clone + Debug,
-    > HeightIndexedPool<T> for InMemoryPoolSection<S>
+impl<T: ConsensusMessageHashable + 'static + Debug, S: PoolSectionStorage> HeightIndexedPool<T>
+    for S
 where
     CryptoHashOf<T>: SelectIndex,
 {
     fn get_by_height(&self, h: Height) -> Box<dyn Iterator<Item = T>> {
-        let hashes = self.select_index().lookup(h).collect();
+        let hashes = CryptoHashOf::<T>::select_index(self.indexes())
+            .lookup(h)
+            .collect();
         // println!("Hashes at height {}: {:?}", h, hashes);
-        let artifacts = self.get_by_hashes(hashes);
+        let artifacts = get_by_hashes(self, hashes);
         // println!("Corresponding artifacts: {:?}", artifacts);
         Box::new(artifacts.into_iter())
     }
@@ -162,7 +236,7 @@ where
         if range.min > range.max {
             return Box::new(std::iter::empty());
         }
-        let heights = CryptoHashOf::<T>::select_index(&self.indexes)
+        let heights = CryptoHashOf::<T>::select_index(self.indexes())
             .range((
                 std::ops::Bound::Included(range.min),
                 std::ops::Bound::Included(range.max),
@@ -177,7 +251,7 @@ where
     }
 
     fn height_range(&self) -> Option<HeightRange> {
-        let heights = CryptoHashOf::<T>::select_index(&self.indexes)
+        let heights = CryptoHashOf::<T>::select_index(self.indexes())
             .heights()
             .cloned()
             .collect::<Vec<_>>();
@@ -209,30 +283,42 @@ where
 }
 
 pub struct ConsensusPoolImpl {
-    validated: Box<InMemoryPoolSection<ValidatedConsensusArtifact>>,
+    validated: Box<dyn MutablePoolSection<ValidatedConsensusArtifact> + Send + Sync>,
     unvalidated: Box<InMemoryPoolSection<UnvalidatedConsensusArtifact>>,
+    invalid_artifacts: Arc<RwLock<InvalidArtifactCounts>>,
+    equivocation_evidence: Arc<RwLock<Vec<EquivocationEvidence>>>,
 }
 
 impl Default for ConsensusPoolImpl {
     fn default() -> Self {
-        Self::new()
+        Self::new(
+            Box::new(InMemoryPoolSection::new()),
+            Arc::default(),
+            Arc::default(),
+        )
     }
 }
 
 impl ConsensusPoolImpl {
-    pub fn new() -> Self {
+    pub fn new(
+        validated: Box<dyn MutablePoolSection<ValidatedConsensusArtifact> + Send + Sync>,
+        invalid_artifacts: Arc<RwLock<InvalidArtifactCounts>>,
+        equivocation_evidence: Arc<RwLock<Vec<EquivocationEvidence>>>,
+    ) -> Self {
         Self {
-            validated: Box::new(InMemoryPoolSection::new()),
+            validated,
             unvalidated: Box::new(InMemoryPoolSection::new()),
+            invalid_artifacts,
+            equivocation_evidence,
         }
     }
 
-    pub fn validated(&self) -> &InMemoryPoolSection<ValidatedConsensusArtifact> {
+    pub fn validated(&self) -> &dyn PoolSection<ValidatedConsensusArtifact> {
         self.validated.pool_section()
     }
 
     pub fn unvalidated(&self) -> &InMemoryPoolSection<UnvalidatedConsensusArtifact> {
-        self.unvalidated.pool_section()
+        &self.unvalidated
     }
 
     pub fn insert(&mut self, unvalidated_artifact: UnvalidatedConsensusArtifact) {
@@ -253,6 +339,7 @@ impl ConsensusPoolImpl {
         for change_action in change_set {
             match change_action {
                 ChangeAction::AddToValidated(to_add) => {
+                    self.record_equivocation_evidence(&to_add);
                     validated_ops.insert(ValidatedConsensusArtifact {
                         msg: to_add,
                         timestamp: system_time_now(),
@@ -262,11 +349,22 @@ impl ConsensusPoolImpl {
                     let msg_id = to_move.get_id();
                     unvalidated_ops.remove(msg_id);
 
+                    self.record_equivocation_evidence(&to_move);
                     validated_ops.insert(ValidatedConsensusArtifact {
                         msg: to_move,
                         timestamp: system_time_now(),
                     });
                 }
+                ChangeAction::RemoveFromValidated(to_remove) => {
+                    validated_ops.remove(to_remove.get_id());
+                }
+                ChangeAction::RemoveFromUnvalidated(to_remove) => {
+                    unvalidated_ops.remove(to_remove.get_id());
+                }
+                ChangeAction::HandleInvalid(to_remove, reason) => {
+                    self.count_invalid_artifact(&to_remove, reason);
+                    unvalidated_ops.remove(to_remove.get_id());
+                }
             }
         }
         self.apply_changes_unvalidated(unvalidated_ops);
@@ -278,28 +376,54 @@ impl ConsensusPoolImpl {
     }
 
     pub fn finalized_block_hash_at_height(&self, height: Height) -> Option<String> {
-        match self
+        // a block can be finalized by several finalizations, e.g. by an FP-finalization and by
+        // finalization shares, or by finalizations aggregated from different signers
+        let finalized_blocks: BTreeSet<String> = self
             .validated()
             .finalization()
             .get_by_height(height)
-            .count()
-        {
+            .map(|finalization| finalization.content.block.get_ref().to_owned())
+            .collect();
+        match finalized_blocks.len() {
             0 => None,
-            1 => Some(
-                self.validated()
-                    .finalization()
-                    .get_by_height(height)
-                    .last()
-                    .unwrap()
-                    .content
-                    .block
-                    .get_ref()
-                    .to_owned(),
-            ),
+            1 => finalized_blocks.into_iter().next(),
             _ => panic!("more than one finalized blocks at the same height"),
         }
     }
 
+    /// Count the rejection of an unvalidated artifact for the peer it was
+    /// received from or, if that peer is unknown, for the replica that signed it.
+    fn count_invalid_artifact(&self, msg: &ConsensusMessage, reason: InvalidArtifactReason) {
+        let sender = match self.unvalidated().get_by_hash(msg.get_cm_hash().digest()) {
+            Some(artifact) if artifact.peer_id != UNKNOWN_SENDER => Some(artifact.peer_id),
+            // the signature is verified before any other check, so the signer can only be trusted if it is valid
+            Some(_) if reason != InvalidArtifactReason::InvalidSignature => msg.signer(),
+            _ => None,
+        };
+        if let Some(sender) = sender {
+            // println!("Rejected artifact from peer {}: {:?}", sender, reason);
+            *self
+                .invalid_artifacts
+                .write()
+                .unwrap()
+                .entry(sender)
+                .or_default()
+                .entry(reason)
+                .or_default() += 1;
+        }
+    }
+
+    /// Keep track of the validated evidence of equivocations, so that it can be inspected.
+    fn record_equivocation_evidence(&self, msg: &ConsensusMessage) {
+        if let ConsensusMessage::EquivocationEvidence(evidence) = msg {
+            // the evidence might be received again after it was purged from the pool
+            let mut equivocation_evidence = self.equivocation_evidence.write().unwrap();
+            if !equivocation_evidence.contains(evidence) {
+                equivocation_evidence.push(evidence.clone());
+            }
+        }
+    }
+
     fn apply_changes_validated(&mut self, ops: PoolSectionOps<ValidatedConsensusArtifact>) {
         if !ops.ops.is_empty() {
             // println!("\n########## Consensus pool ##########");
@@ -343,8 +467,13 @@ impl<T> PoolSectionOps<T> {
 }
 
 fn get_highest_finalized_block(pool: &ConsensusPoolImpl) -> Option<Block> {
-    match pool.validated().finalization().get_highest() {
-        Ok(finalization) => {
+    let highest_finalization = pool
+        .validated()
+        .finalization()
+        .max_height()
+        .and_then(|h| pool.validated().finalization().get_by_height(h).next());
+    match highest_finalization {
+        Some(finalization) => {
             let h = finalization.content.height;
             let block_hash = &finalization.content.block;
             for proposal in pool.validated().block_proposal().get_by_height(h) {
@@ -352,11 +481,23 @@ fn get_highest_finalized_block(pool: &ConsensusPoolImpl) -> Option<Block> {
                     return Some(proposal.content.value);
                 }
             }
+            // a replica which continued from a catch-up package has no proposal at its height,
+            // so the finalized block is resolved like when validating the finalization
+            if let Some(catch_up_package) = pool
+                .validated()
+                .catch_up_package()
+                .get_by_height(h)
+                .find(|catch_up_package| {
+                    catch_up_package.content.block.get_hash() == block_hash.get_ref()
+                })
+            {
+                return Some(catch_up_package.content.block.value);
+            }
             panic!(
                 "Missing validated block proposal matching finalization {:?}",
                 finalization
             )
         }
-        Err(_) => None,
+        None => None,
     }
 }
@@ -1,13 +1,20 @@
 use std::time::Duration;
 
-use crate::{consensus_layer::pool::ConsensusPoolImpl, time_source::system_time_now};
+use crate::{
+    consensus_layer::pool::ConsensusPoolImpl,
+    crypto::{ConsensusMessageHash, CryptoHashOf},
+    time_source::{system_time_now, Time},
+};
 
 use super::{
+    artifacts::{ConsensusMessage, ConsensusMessageHashable, ConsensusMessageId},
     consensus_subcomponents::{
         block_maker::{Block, BlockProposal},
+        catch_up_package_maker::{CatchUpPackage, CatchUpPackageShare},
         finalizer::FinalizationShare,
-        goodifier::IMadeABlockArtifact,
+        goodifier::GoodnessArtifact,
         notary::{NotarizationShare, NotarizationShareContent},
+        random_beacon_maker::{genesis_random_beacon, RandomBeacon, RandomBeaconShare},
     },
     height_index::{Height, HeightRange},
 };
@@ -43,10 +50,46 @@ impl<'a> PoolReader<'a> {
             .count()
     }
 
+    /// Get the valid random beacon at the given height, if it exists.
+    pub fn get_random_beacon(&self, h: Height) -> Option<RandomBeacon> {
+        if h == 0 {
+            return Some(genesis_random_beacon());
+        }
+        self.pool
+            .validated()
+            .random_beacon()
+            .get_by_height(h)
+            .next()
+            .or_else(|| {
+                self.get_catch_up_package_at_height(h)
+                    .map(|catch_up_package| catch_up_package.content.random_beacon)
+            })
+    }
+
+    /// Get the valid random beacon with the greatest height.
+    pub fn get_random_beacon_tip(&self) -> RandomBeacon {
+        let beacon_height = self.pool.validated().random_beacon().max_height();
+        match beacon_height.max(self.get_catch_up_package_height()) {
+            Some(h) => self.get_random_beacon(h).unwrap(),
+            None => genesis_random_beacon(),
+        }
+    }
+
+    /// Get all valid random beacon shares at the given height.
+    pub fn get_random_beacon_shares(
+        &self,
+        h: Height,
+    ) -> Box<dyn Iterator<Item = RandomBeaconShare>> {
+        self.pool.validated().random_beacon_share().get_by_height(h)
+    }
+
     // Get max height of valid notarized blocks.
     pub fn get_notarized_height(&self) -> Height {
         let notarized_height = self.pool.validated().notarization().max_height();
-        notarized_height.unwrap_or(0)
+        // the block of a catch-up package is finalized, and thus notarized
+        notarized_height
+            .unwrap_or(0)
+            .max(self.get_catch_up_height())
     }
 
     /// Get all valid finalization shares in the given height range, inclusive.
@@ -71,37 +114,193 @@ impl<'a> PoolReader<'a> {
 
     /// Get the finalized block with greatest height.
     pub fn get_finalized_tip(&self) -> Option<Block> {
-        self.pool.finalized_block()
+        let finalized_block = self.pool.finalized_block();
+        match self.get_catch_up_package() {
+            Some(catch_up_package)
+                if finalized_block.as_ref().map_or(0, |block| block.height)
+                    < catch_up_package.content.height() =>
+            {
+                Some(catch_up_package.content.block.value)
+            }
+            _ => finalized_block,
+        }
     }
 
     pub fn get_finalized_block_hash_at_height(&self, height: Height) -> Option<String> {
-        self.pool.finalized_block_hash_at_height(height)
+        self.pool
+            .finalized_block_hash_at_height(height)
+            .or_else(|| {
+                self.get_catch_up_package_at_height(height)
+                    .map(|catch_up_package| catch_up_package.content.block.hash)
+            })
+    }
+
+    /// Return the finalized blocks from height `from` to height `to`, inclusive,
+    /// if they are all in the pool. Heights may be finalized implicitly by the
+    /// finalization of a descendant, so the blocks are obtained by following
+    /// the parents of the latest finalized block.
+    pub fn get_finalized_chain(&self, from: Height, to: Height) -> Option<Vec<Block>> {
+        let mut block = self.get_finalized_tip()?;
+        if block.height < to {
+            return None;
+        }
+        let mut chain = Vec::new();
+        while block.height >= from {
+            if block.height <= to {
+                chain.push(block.clone());
+            }
+            if block.height == from {
+                break;
+            }
+            block = self
+                .get_block(&CryptoHashOf::from(block.parent.clone()), block.height - 1)
+                .ok()?;
+        }
+        chain.reverse();
+        Some(chain)
     }
 
     /// Return a valid block with the matching hash and height if it exists.
-    pub fn get_block(&self, h: Height) -> Result<Block, ()> {
+    pub fn get_block(&self, hash: &CryptoHashOf<Block>, h: Height) -> Result<Block, ()> {
         let mut blocks: Vec<BlockProposal> = self
             .pool
             .validated()
             .block_proposal()
             .get_by_height(h)
-            //.filter(|x| x.content.get_hash() == hash.get_ref())
+            .filter(|x| x.content.get_hash() == hash.get_ref())
             .collect();
         match blocks.len() {
             1 => Ok(blocks.remove(0).content.value),
-            _ => Err(()),
+            _ => self
+                .get_catch_up_package_at_height(h)
+                .filter(|catch_up_package| catch_up_package.content.block.hash == *hash.get_ref())
+                .map(|catch_up_package| catch_up_package.content.block.value)
+                .ok_or(()),
+        }
+    }
+
+    /// Return the valid notarized block with the matching hash and height if it
+    /// exists.
+    pub fn get_notarized_block(&self, hash: &CryptoHashOf<Block>, h: Height) -> Option<Block> {
+        let is_notarized = self
+            .pool
+            .validated()
+            .notarization()
+            .get_by_height(h)
+            .any(|notarization| notarization.content.block == *hash);
+        let is_caught_up = self
+            .get_catch_up_package_at_height(h)
+            .is_some_and(|catch_up_package| catch_up_package.content.block.hash == *hash.get_ref());
+        if is_notarized || is_caught_up {
+            self.get_block(hash, h).ok()
+        } else {
+            None
         }
     }
 
     /// Return all valid notarized blocks of a given height.
     pub fn get_notarized_blocks(&'a self, h: Height) -> Box<dyn Iterator<Item = Block> + 'a> {
-        Box::new(
+        let notarized_blocks: Vec<Block> = self
+            .pool
+            .validated()
+            .notarization()
+            .get_by_height(h)
+            // the proposal of a notarized block might have been purged below a catch-up package
+            .filter_map(move |x| self.get_block(&x.content.block, h).ok())
+            .collect();
+        let catch_up_block = self
+            .get_catch_up_package_at_height(h)
+            .map(|catch_up_package| catch_up_package.content.block.value)
+            .filter(|block| !notarized_blocks.contains(block));
+        Box::new(catch_up_block.into_iter().chain(notarized_blocks))
+    }
+
+    /// Return the valid artifacts with which a peer answers a request for the
+    /// given artifact. Block proposals are requested by the hash of their block,
+    /// as other artifacts refer to blocks, and come with the notarizations of
+    /// the block and the random beacon of its height, which validating the
+    /// proposals of the next height requires. A block of a catch-up package is
+    /// answered with the catch-up package.
+    pub fn get_requested_artifacts(&self, request: &ConsensusMessageId) -> Vec<ConsensusMessage> {
+        let block_hash = match &request.hash {
+            ConsensusMessageHash::BlockProposal(block_hash) => block_hash,
+            hash => {
+                return self
+                    .pool
+                    .validated()
+                    .get_by_hash(hash.digest())
+                    .map(|artifact| artifact.msg)
+                    .into_iter()
+                    .collect()
+            }
+        };
+        let h = request.height;
+        let mut artifacts: Vec<ConsensusMessage> = self
+            .pool
+            .validated()
+            .block_proposal()
+            .get_by_height(h)
+            .filter(|proposal| proposal.content.get_hash() == block_hash)
+            .map(ConsensusMessage::BlockProposal)
+            .collect();
+        artifacts.extend(
+            self.get_catch_up_package_at_height(h)
+                .filter(|catch_up_package| catch_up_package.content.block.hash == *block_hash)
+                .map(ConsensusMessage::CatchUpPackage),
+        );
+        if artifacts.is_empty() {
+            return artifacts;
+        }
+        artifacts.extend(
             self.pool
                 .validated()
                 .notarization()
                 .get_by_height(h)
-                .map(move |_x| self.get_block(h).unwrap()),
-        )
+                .filter(|notarization| notarization.content.block.get_ref() == block_hash)
+                .map(ConsensusMessage::Notarization),
+        );
+        artifacts.extend(
+            self.pool
+                .validated()
+                .random_beacon()
+                .get_by_height(h)
+                .map(ConsensusMessage::RandomBeacon),
+        );
+        artifacts
+    }
+
+    /// Get the valid catch-up package with the greatest height, if any.
+    pub fn get_catch_up_package(&self) -> Option<CatchUpPackage> {
+        self.get_catch_up_package_at_height(self.get_catch_up_package_height()?)
+    }
+
+    /// Get the height of the latest catch-up package, or 0 if there is none.
+    pub fn get_catch_up_height(&self) -> Height {
+        self.get_catch_up_package_height().unwrap_or(0)
+    }
+
+    fn get_catch_up_package_height(&self) -> Option<Height> {
+        self.pool.validated().catch_up_package().max_height()
+    }
+
+    // catch-up packages of the same height only differ by the replicas which signed them
+    fn get_catch_up_package_at_height(&self, h: Height) -> Option<CatchUpPackage> {
+        self.pool
+            .validated()
+            .catch_up_package()
+            .get_by_height(h)
+            .next()
+    }
+
+    /// Get all valid catch-up package shares at the given height.
+    pub fn get_catch_up_package_shares(
+        &self,
+        h: Height,
+    ) -> Box<dyn Iterator<Item = CatchUpPackageShare>> {
+        self.pool
+            .validated()
+            .catch_up_package_share()
+            .get_by_height(h)
     }
 
     /*
@@ -116,89 +315,58 @@ impl<'a> PoolReader<'a> {
         }
     }
     */
-    /*
-        pub fn get_goodness_height(&self) -> Height {
-            self.pool
-                .validated()
-                .goodness_artifact()
-                .max_height()
-                .unwrap_or(0)
-        }
-
-        pub fn get_latest_goodness_artifact_for_parent(
-            &self,
-            children_height: Height,
-        ) -> Option<GoodnessArtifact> {
-            if let Some(art) = self
-                .pool
-                .validated()
-                .goodness_artifact()
-                .get_by_height(children_height)
-                .find(|a| a.all_children_good)
-            {
-                Some(art)
-            } else {
-                self.pool
-                    .validated()
-                    .goodness_artifact()
-                    .get_by_height(children_height)
-                    //.filter(|goodness_artifact| goodness_artifact.parent_hash.eq(parent_hash))
-                    .max_by(|first, second| first.timestamp.cmp(&second.timestamp))
-            }
-        }
-    */
-    /*pub fn exists_goodness_artifact_for_parent(
+    /// Return the latest goodness artifact for the children of the given
+    /// parent, which is the one stating that all children are good if there
+    /// is one.
+    pub fn get_latest_goodness_artifact_for_parent(
         &self,
-        parent_hash: &String,
-        height: Height,
-    ) -> bool {
-        self.get_latest_goodness_artifact_for_parent(height)
-            .is_some()
-    }*/
-
-    /// Get the round start time of a given height, which is the max timestamp
-    /// of first notarization and random beacon of the previous height.
-    /// Return None if a timestamp is not found.
-    /// This is somehwat unfavorable to FICC, since a round only starts with goodness of
-    /// a notarized block. However, this upper bound makes for a good comparison still.
-    /*pub fn get_round_start_time(&self, height: Height) -> Option<Time> {
-        let validated = self.pool.validated();
+        parent_hash: &str,
+        children_height: Height,
+    ) -> Option<GoodnessArtifact> {
+        let goodness_artifacts: Vec<GoodnessArtifact> = self
+            .pool
+            .validated()
+            .goodness_artifact()
+            .get_by_height(children_height)
+            .filter(|goodness_artifact| goodness_artifact.parent_hash == parent_hash)
+            .collect();
+        goodness_artifacts
+            .iter()
+            .find(|goodness_artifact| goodness_artifact.all_children_good)
+            .or_else(|| {
+                goodness_artifacts
+                    .iter()
+                    .max_by_key(|goodness_artifact| goodness_artifact.timestamp)
+            })
+            .cloned()
+    }
 
-        let get_notarization_time = |h| {
-            validated
-                .notarization()
-                .get_by_height(h)
-                .flat_map(|x| validated.get_timestamp(&x.get_id()))
-                .min()
-        };
-        let prev_height = height - 1;
-        get_notarization_time(prev_height) //.map(|notarization_time| notarization_time)
-    }*/
+    /// Get the round start time of a given height, which is the earliest
+    /// timestamp of the notarizations of the previous height.
+    /// Return None if no notarization of the previous height is in the pool.
+    pub fn get_round_start_time(&self, height: Height) -> Option<Time> {
+        let validated = self.pool.validated();
+        validated
+            .notarization()
+            .get_by_height(height.checked_sub(1)?)
+            .flat_map(|notarization| validated.get_timestamp(&notarization.get_id()))
+            .min()
+    }
 
-    pub fn get_finalization_time(&self, height: Height, _my_node_id: u8) -> Duration {
+    /// Return the time elapsed since the local replica proposed a block at the
+    /// given height or, if it did not propose one, since the round started.
+    pub fn get_finalization_time(&self, height: Height, my_node_id: u8) -> Duration {
         let current_time = system_time_now();
-        let i_produced = self
+        let start_time = self
             .pool
             .validated()
             .i_made_a_block_artifact()
             .get_by_height(height)
-            .next() //.find(|art| art.my_id == my_node_id)
-            .unwrap_or(IMadeABlockArtifact {
-                block_height: height,
-                maker_time: current_time, //Time(current_time.0 - Duration::from_secs(30).as_nanos() as u64),
-                my_id: 0,
-            });
-        /*
-        let val = self.pool.validated();
-        let mast = val
-            .notarization()
-            .get_by_height(height - 1)
-            .flat_map(|x| val.get_timestamp(&x.get_id()))
-            .min();
-        */
-        //if let Some(_round_start_time) = self.get_round_start_time(height) {
-        current_time - i_produced.maker_time //- mast.unwrap_or(current_time);
-                                             //}
-                                             //None
+            .find(|artifact| artifact.my_id == my_node_id)
+            .map(|artifact| artifact.maker_time)
+            // "I made a block" artifacts are never received from peers, so blocks of other replicas are timed locally
+            .or_else(|| self.get_round_start_time(height))
+            .unwrap_or(current_time);
+        current_time - start_time
     }
 }
@@ -1,62 +1,599 @@
+use bls12_381::{
+    hash_to_curve::{ExpandMsgXmd, HashToCurve},
+    pairing, G1Affine, G1Projective, G2Affine, G2Projective, Scalar,
+};
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier};
+use libp2p::{identity, PeerId};
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
-//use sha2::{Digest, Sha256};
-use std::{hash::Hash, marker::PhantomData};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet},
+    fs,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+};
 
 // Signed contains the signed content and its signature.
-#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct Signed<T, S> {
     pub content: T,
     pub signature: S,
 }
 
-pub trait TurboHash {
-    fn tubro_hash(&self) -> String;
+/// BasicSignature is the Ed25519 signature of a single replica on the
+/// serialized content of an artifact.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct BasicSignature {
+    pub signer: u8,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+/// MultiSignature aggregates the signatures of several replicas on the same
+/// content. Signers are recorded in a bitmap indexed by replica number and
+/// their signatures are stored in ascending order of replica number.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct MultiSignature {
+    #[serde(with = "serde_bytes")]
+    pub signers: Vec<u8>,
+    pub signatures: Vec<ByteBuf>,
+}
+
+impl MultiSignature {
+    /// Return the replica numbers of the signers, in ascending order. Return
+    /// None if the bitmap is longer than the one of a subnet of
+    /// `total_nodes_number` replicas or if it has bits set for non-members.
+    pub fn signers(&self, total_nodes_number: u8) -> Option<Vec<u8>> {
+        if self.signers.len() > total_nodes_number as usize / 8 + 1 {
+            return None;
+        }
+        (0..self.signers.len() * 8)
+            .filter(|i| self.signers[i / 8] & (1 << (i % 8)) != 0)
+            .map(|i| {
+                u8::try_from(i)
+                    .ok()
+                    .filter(|signer| (1..=total_nodes_number).contains(signer))
+            })
+            .collect()
+    }
+}
+
+/// ThresholdSignatureShare is the share of a replica of a BLS threshold
+/// signature.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ThresholdSignatureShare {
+    pub signer: u8,
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+/// ThresholdSignature is a BLS threshold signature combined from the shares of
+/// enough replicas. Unlike a [MultiSignature], it does not depend on which
+/// replicas contributed a share, so it is unique for a given content.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ThresholdSignature {
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+}
+
+/// Domain separation tag used to hash threshold signed content to the curve.
+const THRESHOLD_SIGNATURE_DST: &[u8] = b"FICC_BLS_SIG_BLS12381G1_XMD:SHA-256_SSWU_RO_";
+
+/// Name of the file in the key directory listing the public keys of the subnet.
+const MEMBERSHIP_FILE: &str = "membership.json";
+
+/// Membership lists the public keys of the replicas of the subnet, indexed by
+/// replica number, together with the public key of the threshold key of the
+/// subnet. Keys are hex encoded.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Membership {
+    pub replicas: BTreeMap<u8, ReplicaPublicKeys>,
+    pub threshold_public_key: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReplicaPublicKeys {
+    pub peer_id: String,
+    pub signing_public_key: String,
+    pub threshold_public_key_share: String,
+}
+
+impl Membership {
+    /// Read the membership file from `key_dir`, if it exists.
+    pub fn load(key_dir: &Path) -> Option<Self> {
+        let bytes = fs::read(key_dir.join(MEMBERSHIP_FILE)).ok()?;
+        Some(serde_json::from_slice(&bytes).expect("can parse membership file"))
+    }
+
+    /// Return the replica number of each peer ID listed in the membership.
+    pub fn peer_ids(&self) -> BTreeMap<PeerId, u8> {
+        self.replicas
+            .iter()
+            .map(|(id, keys)| (keys.peer_id.parse().expect("valid peer ID"), *id))
+            .collect()
+    }
+}
+
+/// CryptoProvider signs the artifacts of the local replica and verifies the
+/// signatures of the artifacts of the other replicas. Messages are the
+/// serialized contents returned by [to_bytes]. The hash is used to identify
+/// blocks and random beacons, but not artifacts (see [Hashed::crypto_hash]).
+pub trait CryptoProvider: Send + Sync {
+    /// Sign `message` with the key of the local replica.
+    fn sign(&self, message: &[u8]) -> BasicSignature;
+
+    /// Return true if `signature` is a valid signature on `message` by a
+    /// replica of the subnet.
+    fn verify(&self, message: &[u8], signature: &BasicSignature) -> bool;
+
+    /// Return the hex encoded hash of `message`.
+    fn hash(&self, message: &[u8]) -> CryptoHash;
+
+    /// Aggregate individual signatures on the same message into a
+    /// [MultiSignature]. Only one signature per signer is kept.
+    fn aggregate(&self, signatures: Vec<BasicSignature>) -> MultiSignature;
+
+    /// Return true if `multi_signature` contains valid signatures on `message`
+    /// from at least `threshold` distinct replicas of the subnet.
+    fn verify_aggregate(
+        &self,
+        message: &[u8],
+        multi_signature: &MultiSignature,
+        threshold: usize,
+    ) -> bool;
+
+    /// Sign `message` with the threshold key share of the local replica.
+    fn threshold_sign_share(&self, message: &[u8]) -> ThresholdSignatureShare;
+
+    /// Return true if `share` is a valid threshold signature share on
+    /// `message` by a replica of the subnet.
+    fn verify_threshold_share(&self, message: &[u8], share: &ThresholdSignatureShare) -> bool;
+
+    /// Combine threshold signature shares on the same message into a
+    /// [ThresholdSignature]. Return None if there are fewer than `threshold`
+    /// shares from distinct signers.
+    fn combine_threshold_shares(
+        &self,
+        shares: Vec<ThresholdSignatureShare>,
+    ) -> Option<ThresholdSignature>;
+
+    /// Return true if `signature` is a valid threshold signature of the subnet
+    /// on `message`.
+    fn verify_threshold_signature(&self, message: &[u8], signature: &ThresholdSignature) -> bool;
+}
+
+/// KeyStore holds the signing key of the local replica and the public keys of
+/// all the replicas in the subnet, as well as the share of the local replica
+/// of the threshold signing key of the subnet. It provides Ed25519 signatures,
+/// SHA-256 hashes and BLS threshold signatures.
+pub struct KeyStore {
+    node_id: u8,
+    keypair: Keypair,
+    public_keys: BTreeMap<u8, PublicKey>,
+    threshold: usize,
+    threshold_secret_key_share: Scalar,
+    threshold_public_key_shares: BTreeMap<u8, G2Affine>,
+    threshold_public_key: G2Affine,
+}
+
+impl KeyStore {
+    /// Load the signing key and the threshold key share of the local replica
+    /// from `key_dir`, and the public keys of the subnet from `membership`.
+    pub fn load(key_dir: &Path, node_id: u8, membership: &Membership, threshold: usize) -> Self {
+        let keypair =
+            Keypair::from_bytes(&read_key(key_dir, node_id, "signing")).expect("valid signing key");
+        let public_keys: BTreeMap<u8, PublicKey> = membership
+            .replicas
+            .iter()
+            .map(|(id, keys)| {
+                let bytes = hex::decode(&keys.signing_public_key).expect("hex encoded key");
+                (
+                    *id,
+                    PublicKey::from_bytes(&bytes).expect("valid public key"),
+                )
+            })
+            .collect();
+        assert_eq!(
+            public_keys.get(&node_id),
+            Some(&keypair.public),
+            "signing key of replica {} does not match the membership file",
+            node_id
+        );
+        let secret_key_share: [u8; 32] = read_key(key_dir, node_id, "threshold")
+            .try_into()
+            .expect("threshold key share is 32 bytes long");
+        Self {
+            node_id,
+            keypair,
+            public_keys,
+            threshold,
+            threshold_secret_key_share: Option::from(Scalar::from_bytes(&secret_key_share))
+                .expect("valid threshold key share"),
+            threshold_public_key_shares: membership
+                .replicas
+                .iter()
+                .map(|(id, keys)| (*id, g2_from_hex(&keys.threshold_public_key_share)))
+                .collect(),
+            threshold_public_key: g2_from_hex(&membership.threshold_public_key),
+        }
+    }
+
+    fn verify_bytes(&self, bytes: &[u8], signer: u8, signature: &[u8]) -> bool {
+        let public_key = match self.public_keys.get(&signer) {
+            Some(public_key) => public_key,
+            None => return false,
+        };
+        match Signature::from_bytes(signature) {
+            Ok(signature) => public_key.verify(bytes, &signature).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Generate the network key, the signing key and the threshold key share of
+/// each replica of the subnet, store them in `key_dir` together with the
+/// membership file and return the membership. The threshold key is shared by a
+/// trusted dealer, such that any `threshold` replicas can sign with it.
+pub fn generate_subnet_keys(
+    key_dir: &Path,
+    total_nodes_number: u8,
+    threshold: usize,
+) -> Membership {
+    let coefficients: Vec<Scalar> = (0..threshold)
+        .map(|_| {
+            let mut bytes = [0u8; 64];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            Scalar::from_bytes_wide(&bytes)
+        })
+        .collect();
+    let replicas = (1..=total_nodes_number)
+        .map(|id| {
+            let network_key = identity::ed25519::Keypair::generate();
+            let signing_key = identity::ed25519::Keypair::generate();
+            let threshold_key_share = evaluate_polynomial(&coefficients, id);
+            write_key(key_dir, id, "network", &network_key.encode());
+            write_key(key_dir, id, "signing", &signing_key.encode());
+            write_key(key_dir, id, "threshold", &threshold_key_share.to_bytes());
+            let keys = ReplicaPublicKeys {
+                peer_id: PeerId::from(identity::PublicKey::Ed25519(network_key.public()))
+                    .to_string(),
+                signing_public_key: hex::encode(signing_key.public().encode()),
+                threshold_public_key_share: hex::encode(
+                    G2Affine::from(G2Projective::generator() * threshold_key_share).to_compressed(),
+                ),
+            };
+            (id, keys)
+        })
+        .collect();
+    let membership = Membership {
+        replicas,
+        threshold_public_key: hex::encode(
+            G2Affine::from(G2Projective::generator() * coefficients[0]).to_compressed(),
+        ),
+    };
+    fs::write(
+        key_dir.join(MEMBERSHIP_FILE),
+        serde_json::to_vec_pretty(&membership).unwrap(),
+    )
+    .expect("can write membership file");
+    membership
+}
+
+/// Load the network key of the local replica from `key_dir`. The network key
+/// determines the peer ID of the replica, which therefore does not change
+/// across restarts.
+pub fn load_network_key(key_dir: &Path, node_id: u8) -> identity::Keypair {
+    let mut bytes = read_key(key_dir, node_id, "network");
+    let keypair = identity::ed25519::Keypair::decode(&mut bytes).expect("valid network key");
+    identity::Keypair::Ed25519(keypair)
+}
+
+fn key_path(key_dir: &Path, node_id: u8, key_type: &str) -> PathBuf {
+    key_dir.join(format!("replica_{}_{}.key", node_id, key_type))
+}
+
+fn read_key(key_dir: &Path, node_id: u8, key_type: &str) -> Vec<u8> {
+    let path = key_path(key_dir, node_id, key_type);
+    fs::read(&path).unwrap_or_else(|err| panic!("cannot read key {:?}: {}", path, err))
+}
+
+fn write_key(key_dir: &Path, node_id: u8, key_type: &str, bytes: &[u8]) {
+    fs::create_dir_all(key_dir).expect("can create key directory");
+    let path = key_path(key_dir, node_id, key_type);
+    fs::write(&path, bytes).unwrap_or_else(|err| panic!("cannot write key {:?}: {}", path, err));
+}
+
+fn g2_from_hex(key: &str) -> G2Affine {
+    let bytes: [u8; 96] = hex::decode(key)
+        .expect("hex encoded key")
+        .try_into()
+        .expect("threshold public key is 96 bytes long");
+    Option::from(G2Affine::from_compressed(&bytes)).expect("valid threshold public key")
 }
 
-impl<T: TurboHash, S: ToString> TurboHash for Signed<T, S> {
-    fn tubro_hash(&self) -> String {
-        format!(
-            "signed{}.{}",
-            self.content.tubro_hash(),
-            self.signature.to_string()
-        )
+impl CryptoProvider for KeyStore {
+    fn sign(&self, message: &[u8]) -> BasicSignature {
+        BasicSignature {
+            signer: self.node_id,
+            signature: self.keypair.sign(message).to_bytes().to_vec(),
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &BasicSignature) -> bool {
+        self.verify_bytes(message, signature.signer, &signature.signature)
+    }
+
+    fn hash(&self, message: &[u8]) -> CryptoHash {
+        hex::encode(Sha256::digest(message).as_slice())
     }
+
+    fn aggregate(&self, signatures: Vec<BasicSignature>) -> MultiSignature {
+        let by_signer: BTreeMap<u8, Vec<u8>> = signatures
+            .into_iter()
+            .map(|signature| (signature.signer, signature.signature))
+            .collect();
+        MultiSignature {
+            signers: signer_bitmap(self.public_keys.len(), by_signer.keys()),
+            signatures: by_signer.into_values().map(ByteBuf::from).collect(),
+        }
+    }
+
+    fn verify_aggregate(
+        &self,
+        message: &[u8],
+        multi_signature: &MultiSignature,
+        threshold: usize,
+    ) -> bool {
+        let signers = match multi_signature.signers(self.public_keys.len() as u8) {
+            Some(signers) => signers,
+            None => return false,
+        };
+        // only distinct signers count towards the threshold
+        let distinct_signers: BTreeSet<&u8> = signers.iter().collect();
+        if distinct_signers.len() < threshold || signers.len() != multi_signature.signatures.len()
+        {
+            return false;
+        }
+        signers
+            .into_iter()
+            .zip(multi_signature.signatures.iter())
+            .all(|(signer, signature)| self.verify_bytes(message, signer, signature))
+    }
+
+    fn threshold_sign_share(&self, message: &[u8]) -> ThresholdSignatureShare {
+        let signature = hash_to_g1(message) * self.threshold_secret_key_share;
+        ThresholdSignatureShare {
+            signer: self.node_id,
+            signature: G1Affine::from(signature).to_compressed().to_vec(),
+        }
+    }
+
+    fn verify_threshold_share(&self, message: &[u8], share: &ThresholdSignatureShare) -> bool {
+        match self.threshold_public_key_shares.get(&share.signer) {
+            Some(public_key_share) => verify_bls(message, &share.signature, public_key_share),
+            None => false,
+        }
+    }
+
+    fn combine_threshold_shares(
+        &self,
+        shares: Vec<ThresholdSignatureShare>,
+    ) -> Option<ThresholdSignature> {
+        let by_signer: BTreeMap<u8, G1Affine> = shares
+            .into_iter()
+            .filter_map(|share| Some((share.signer, g1_from_bytes(&share.signature)?)))
+            .collect::<BTreeMap<_, _>>()
+            .into_iter()
+            .take(self.threshold)
+            .collect();
+        if by_signer.len() < self.threshold {
+            return None;
+        }
+        let signature =
+            by_signer
+                .iter()
+                .fold(G1Projective::identity(), |signature, (signer, share)| {
+                    signature + share * lagrange_coefficient(*signer, by_signer.keys())
+                });
+        Some(ThresholdSignature {
+            signature: G1Affine::from(signature).to_compressed().to_vec(),
+        })
+    }
+
+    fn verify_threshold_signature(&self, message: &[u8], signature: &ThresholdSignature) -> bool {
+        verify_bls(message, &signature.signature, &self.threshold_public_key)
+    }
+}
+
+/// FakeCryptoProvider only keeps track of the signers of artifacts, without
+/// computing or checking any signature. Its hash is a non-cryptographic 64 bit
+/// hash. It is meant for large simulations, in which the cost of cryptography
+/// should not be measured.
+pub struct FakeCryptoProvider {
+    node_id: u8,
+    total_nodes_number: u8,
+    threshold: usize,
+}
+
+impl FakeCryptoProvider {
+    pub fn new(node_id: u8, total_nodes_number: u8, threshold: usize) -> Self {
+        Self {
+            node_id,
+            total_nodes_number,
+            threshold,
+        }
+    }
+
+    fn is_member(&self, signer: u8) -> bool {
+        (1..=self.total_nodes_number).contains(&signer)
+    }
+}
+
+impl CryptoProvider for FakeCryptoProvider {
+    fn sign(&self, _message: &[u8]) -> BasicSignature {
+        BasicSignature {
+            signer: self.node_id,
+            signature: vec![],
+        }
+    }
+
+    fn verify(&self, _message: &[u8], signature: &BasicSignature) -> bool {
+        self.is_member(signature.signer)
+    }
+
+    fn hash(&self, message: &[u8]) -> CryptoHash {
+        let mut hasher = DefaultHasher::new();
+        message.hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+
+    fn aggregate(&self, signatures: Vec<BasicSignature>) -> MultiSignature {
+        let signers: BTreeSet<u8> = signatures
+            .into_iter()
+            .map(|signature| signature.signer)
+            .collect();
+        MultiSignature {
+            signers: signer_bitmap(self.total_nodes_number as usize, signers.iter()),
+            signatures: signers.iter().map(|_| ByteBuf::new()).collect(),
+        }
+    }
+
+    fn verify_aggregate(
+        &self,
+        _message: &[u8],
+        multi_signature: &MultiSignature,
+        threshold: usize,
+    ) -> bool {
+        match multi_signature.signers(self.total_nodes_number) {
+            // only distinct signers count towards the threshold
+            Some(signers) => signers.iter().collect::<BTreeSet<_>>().len() >= threshold,
+            None => false,
+        }
+    }
+
+    fn threshold_sign_share(&self, _message: &[u8]) -> ThresholdSignatureShare {
+        ThresholdSignatureShare {
+            signer: self.node_id,
+            signature: vec![],
+        }
+    }
+
+    fn verify_threshold_share(&self, _message: &[u8], share: &ThresholdSignatureShare) -> bool {
+        self.is_member(share.signer)
+    }
+
+    fn combine_threshold_shares(
+        &self,
+        shares: Vec<ThresholdSignatureShare>,
+    ) -> Option<ThresholdSignature> {
+        let signers: BTreeSet<u8> = shares.into_iter().map(|share| share.signer).collect();
+        if signers.len() < self.threshold {
+            return None;
+        }
+        Some(ThresholdSignature { signature: vec![] })
+    }
+
+    fn verify_threshold_signature(&self, _message: &[u8], _signature: &ThresholdSignature) -> bool {
+        true
+    }
+}
+
+/// Return the bitmap, indexed by replica number, of the given signers.
+fn signer_bitmap<'a>(total_nodes_number: usize, signers: impl Iterator<Item = &'a u8>) -> Vec<u8> {
+    let mut bitmap = vec![0u8; total_nodes_number / 8 + 1];
+    for signer in signers {
+        bitmap[*signer as usize / 8] |= 1 << (signer % 8);
+    }
+    bitmap
+}
+
+fn evaluate_polynomial(coefficients: &[Scalar], x: u8) -> Scalar {
+    coefficients
+        .iter()
+        .rev()
+        .fold(Scalar::zero(), |value, coefficient| {
+            value * Scalar::from(x as u64) + coefficient
+        })
+}
+
+/// Return the Lagrange coefficient of `signer` for interpolating at zero the
+/// polynomial evaluated at `signers`.
+fn lagrange_coefficient<'a>(signer: u8, signers: impl Iterator<Item = &'a u8>) -> Scalar {
+    let x_i = Scalar::from(signer as u64);
+    signers
+        .filter(|other| **other != signer)
+        .fold(Scalar::one(), |coefficient, other| {
+            let x_j = Scalar::from(*other as u64);
+            coefficient * x_j * (x_j - x_i).invert().unwrap()
+        })
+}
+
+fn hash_to_g1(bytes: &[u8]) -> G1Projective {
+    <G1Projective as HashToCurve<ExpandMsgXmd<Sha256>>>::hash_to_curve(
+        bytes,
+        THRESHOLD_SIGNATURE_DST,
+    )
+}
+
+fn g1_from_bytes(bytes: &[u8]) -> Option<G1Affine> {
+    let bytes: [u8; 48] = bytes.try_into().ok()?;
+    Option::from(G1Affine::from_compressed(&bytes))
+}
+
+fn verify_bls(bytes: &[u8], signature: &[u8], public_key: &G2Affine) -> bool {
+    match g1_from_bytes(signature) {
+        Some(signature) => {
+            pairing(&signature, &G2Affine::generator())
+                == pairing(&G1Affine::from(hash_to_g1(bytes)), public_key)
+        }
+        None => false,
+    }
+}
+
+/// Domain separates the messages that are signed or hashed, such that contents
+/// of different types never result in the same message, even if they are
+/// serialized alike.
+pub trait Domain {
+    const DOMAIN: &'static str;
+}
+
+/// Serialize `content`, prefixed by its domain, into the message that is signed
+/// or hashed.
+pub fn to_bytes<T: Serialize + Domain>(content: &T) -> Vec<u8> {
+    let mut bytes = T::DOMAIN.as_bytes().to_vec();
+    bytes.extend(serde_json::to_vec(content).expect("can serialize signed content"));
+    bytes
 }
 
 /// Bundle of both a value and its hash. Once created it remains immutable,
 /// which is why both fields are only accessible through member functions, not
 /// as record fields.
-#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub struct Hashed<T> {
     pub(crate) hash: CryptoHash,
     pub(crate) value: T,
 }
 
-impl<T: Serialize + TurboHash> Hashed<T> {
-    pub fn new(artifact: T) -> Self {
-        Self {
-            hash: Hashed::crypto_hash(&artifact),
-            value: artifact,
-        }
-    }
-
+impl<T: Serialize> Hashed<T> {
     /// Return the hash field as reference.
     pub fn get_hash(&self) -> &CryptoHash {
         &self.hash
     }
 
+    /// Return the hex encoded SHA-256 digest of the serialized artifact.
+    ///
+    /// This is only used for the IDs of the artifacts in the pool and on the
+    /// network, which are computed where no [CryptoProvider] is available and
+    /// must not collide with the fake crypto either. Hashes that are signed or
+    /// validated, e.g. those of blocks, use [CryptoProvider::hash] on [to_bytes].
     pub fn crypto_hash(artifact: &T) -> CryptoHash {
-        //let payload = serde_json::json!(artifact);
-        //let mut hasher = Sha256::new();
-        //hasher.update(payload.to_string().as_bytes());
-        //hex::encode(hasher.finalize().as_slice())
-        artifact.tubro_hash()
-    }
-}
-
-impl<T: TurboHash> TurboHash for Hashed<T> {
-    fn tubro_hash(&self) -> String {
-        format!("hashed{}", self.hash.clone())
+        let payload = serde_json::to_vec(artifact).expect("can serialize hashed artifact");
+        let mut hasher = Sha256::new();
+        hasher.update(&payload);
+        hex::encode(hasher.finalize().as_slice())
     }
 }
 
@@ -95,6 +632,11 @@ pub enum ConsensusMessageHash {
     Finalization(CryptoHash),
     GoodnessArtifact(CryptoHash),
     IMadeABlockArtifact(CryptoHash),
+    RandomBeaconShare(CryptoHash),
+    RandomBeacon(CryptoHash),
+    EquivocationEvidence(CryptoHash),
+    CatchUpPackageShare(CryptoHash),
+    CatchUpPackage(CryptoHash),
 }
 
 impl ConsensusMessageHash {
@@ -107,6 +649,107 @@ impl ConsensusMessageHash {
             ConsensusMessageHash::FinalizationShare(hash) => hash,
             ConsensusMessageHash::GoodnessArtifact(hash) => hash,
             ConsensusMessageHash::IMadeABlockArtifact(hash) => hash,
+            ConsensusMessageHash::RandomBeaconShare(hash) => hash,
+            ConsensusMessageHash::RandomBeacon(hash) => hash,
+            ConsensusMessageHash::EquivocationEvidence(hash) => hash,
+            ConsensusMessageHash::CatchUpPackageShare(hash) => hash,
+            ConsensusMessageHash::CatchUpPackage(hash) => hash,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn multi_signature(signers: Vec<u8>, signatures: usize) -> MultiSignature {
+        MultiSignature {
+            signers,
+            signatures: vec![ByteBuf::new(); signatures],
+        }
+    }
+
+    fn key_stores(key_dir: &Path, total_nodes_number: u8, threshold: usize) -> Vec<KeyStore> {
+        let membership = generate_subnet_keys(key_dir, total_nodes_number, threshold);
+        (1..=total_nodes_number)
+            .map(|node_id| KeyStore::load(key_dir, node_id, &membership, threshold))
+            .collect()
+    }
+
+    #[test]
+    fn signer_bitmap_round_trip() {
+        for total_nodes_number in [1, 7, 8, 9, 16, 40] {
+            let signers: Vec<u8> = (1..=total_nodes_number).step_by(3).collect();
+            let bitmap = signer_bitmap(total_nodes_number as usize, signers.iter());
+            assert_eq!(bitmap.len(), total_nodes_number as usize / 8 + 1);
+            assert_eq!(
+                multi_signature(bitmap, signers.len()).signers(total_nodes_number),
+                Some(signers)
+            );
+        }
+    }
+
+    #[test]
+    fn signers_reject_out_of_range_indices() {
+        // replica numbers start at 1, so bit 0 is never set
+        assert_eq!(multi_signature(vec![0b0000_0001], 1).signers(4), None);
+        // replica 5 is not a member of a subnet of 4 replicas
+        assert_eq!(multi_signature(vec![0b0010_0000], 1).signers(4), None);
+        // the bitmap of a subnet of 4 replicas is a single byte
+        assert_eq!(multi_signature(vec![0b0000_0010, 0], 1).signers(4), None);
+        assert_eq!(
+            multi_signature(vec![0b0000_0010], 1).signers(4),
+            Some(vec![1])
+        );
+    }
+
+    #[test]
+    fn verify_aggregate_requires_threshold_distinct_signers() {
+        let key_dir = tempfile::tempdir().unwrap();
+        let key_stores = key_stores(key_dir.path(), 4, 2);
+        let message = b"message";
+        let signatures: Vec<BasicSignature> = key_stores[..2]
+            .iter()
+            .map(|key_store| key_store.sign(message))
+            .collect();
+
+        let aggregate = key_stores[0].aggregate(signatures.clone());
+        assert!(key_stores[3].verify_aggregate(message, &aggregate, 2));
+        assert!(!key_stores[3].verify_aggregate(message, &aggregate, 3));
+        assert!(!key_stores[3].verify_aggregate(b"other message", &aggregate, 2));
+
+        // repeating a signature does not count its signer twice
+        let repeated = key_stores[0].aggregate(vec![signatures[0].clone(), signatures[0].clone()]);
+        assert!(!key_stores[3].verify_aggregate(message, &repeated, 2));
+
+        // the signatures must match the signers of the bitmap
+        let mut extra_signature = aggregate.clone();
+        extra_signature
+            .signatures
+            .push(extra_signature.signatures[0].clone());
+        assert!(!key_stores[3].verify_aggregate(message, &extra_signature, 2));
+    }
+
+    #[test]
+    fn fake_verify_aggregate_requires_threshold_distinct_signers() {
+        let crypto = FakeCryptoProvider::new(1, 4, 2);
+        let signatures = vec![
+            BasicSignature {
+                signer: 1,
+                signature: vec![],
+            },
+            BasicSignature {
+                signer: 1,
+                signature: vec![],
+            },
+            BasicSignature {
+                signer: 2,
+                signature: vec![],
+            },
+        ];
+        let aggregate = crypto.aggregate(signatures);
+        assert!(crypto.verify_aggregate(b"message", &aggregate, 2));
+        assert!(!crypto.verify_aggregate(b"message", &aggregate, 3));
+        assert!(!crypto.verify_aggregate(b"message", &multi_signature(vec![0b0010_0010], 2), 2));
+    }
+}
@@ -5,15 +5,17 @@ use futures::{
     prelude::{stream::StreamExt, *},
     select,
 };
+use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
 use serde_json::to_string;
 use std::{
     collections::BTreeMap,
+    path::PathBuf,
     sync::{Arc, RwLock},
     thread,
     time::Duration,
 };
-use structopt::StructOpt;
+use structopt::{clap, StructOpt};
 use tide::{Body, Request, Response, Result};
 use time_source::Time;
 
@@ -32,7 +34,10 @@ pub struct HeightMetrics {
 
 #[derive(Serialize, Deserialize, Debug)]
 struct BenchmarkResult {
+    parent_finalized_rule: bool,
+    ack_policy: String,
     finalization_times: BTreeMap<Height, Option<HeightMetrics>>,
+    invalid_artifacts: InvalidArtifactCounts,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -43,8 +48,16 @@ pub struct ArtifactDelayInfo {
 
 pub mod network_layer;
 use crate::{
-    consensus_layer::height_index::Height,
-    network_layer::Peer,
+    consensus_layer::{
+        artifacts::InvalidArtifactCounts,
+        consensus_subcomponents::{notary::AckPolicy, validator::EquivocationEvidence},
+        height_index::Height,
+    },
+    crypto::{
+        generate_subnet_keys, load_network_key, CryptoProvider,
+        FakeCryptoProvider, KeyStore, Membership,
+    },
+    network_layer::{GossipsubParams, Peer, WireEncoding},
     time_source::{get_absolute_end_time, system_time_now},
 };
 
@@ -67,14 +80,22 @@ struct Opt {
     p: u8, // number of disagreeing nodes
     #[structopt(long)]
     cod: bool, // enable Fast IC Consensus
+    #[structopt(name = "parent_finalized_rule", long, requires = "cod")]
+    parent_finalized_rule: bool, // FP-finalize a block only if its parent is finalized
+    #[structopt(name = "ack_policy", long, default_value = "first_block_per_height", possible_values = &["first_block_per_height", "first_child"])]
+    ack_policy: String, // "first_child" to acknowledge the first child of each block, as in the paper, instead of the first block of each height, which requires the parent finalized rule
     #[structopt(long, default_value = "300")]
     t: u64, // time to run replica
     #[structopt(long, default_value = "500")]
-    d: u64, // notary delay
+    d: u64, // notary delay, and block maker delay between consecutive ranks
     #[structopt(long, default_value = "56789")]
     port: u64, // port which the peers listen for connections
     #[structopt(name = "broadcast_interval", long, default_value = "100")]
     broadcast_interval: u64, // interval after which artifacts are broadcasted
+    #[structopt(name = "max_batch_size", long, default_value = "20")]
+    max_batch_size: usize, // maximum number of locally generated artifacts published in one network message
+    #[structopt(name = "batch_deadline", long, default_value = "0")]
+    batch_deadline: u64, // time in milliseconds for which an artifact may wait for others to be published with, 0 to publish right away
     #[structopt(
         name = "artifact_manager_polling_interval",
         long,
@@ -85,6 +106,34 @@ struct Opt {
     broadcast_interval_ramp_up: u64, // interval after which artifacts are broadcasted during ramp up in milliseconds
     #[structopt(name = "ramp_up_time", long, default_value = "100")]
     ramp_up_time: u64, // time to ramp up replica in seconds
+    #[structopt(name = "key_dir", long, default_value = "./keys")]
+    key_dir: PathBuf, // directory holding the key material of the replicas and the membership file
+    #[structopt(name = "generate_subnet_keys", long)]
+    generate_subnet_keys: bool, // generate the key material of all the replicas in key_dir and exit
+    #[structopt(long, default_value = "real", possible_values = &["fake", "real"])]
+    crypto: String, // "real" for Ed25519 and SHA-256, "fake" to skip cryptography in large simulations
+    #[structopt(name = "wire_encoding", long, default_value = "binary", possible_values = &["binary", "json"])]
+    wire_encoding: String, // encoding of the network messages, "json" to make them readable when debugging
+    #[structopt(long)]
+    gossipsub: bool, // broadcast artifacts with gossipsub instead of floodsub
+    #[structopt(name = "mesh_n", long, default_value = "6")]
+    mesh_n: usize, // number of peers in the gossipsub mesh
+    #[structopt(name = "mesh_n_low", long, default_value = "4")]
+    mesh_n_low: usize, // number of peers in the gossipsub mesh below which peers are added to it
+    #[structopt(name = "mesh_n_high", long, default_value = "12")]
+    mesh_n_high: usize, // number of peers in the gossipsub mesh above which peers are removed from it
+    #[structopt(name = "gossip_lazy", long, default_value = "6")]
+    gossip_lazy: usize, // number of peers outside the gossipsub mesh to which the ids of recent messages are gossiped
+    #[structopt(name = "heartbeat_interval", long, default_value = "1000")]
+    heartbeat_interval: u64, // interval in milliseconds at which the gossipsub mesh is maintained
+    #[structopt(name = "purge_depth", long, default_value = "10")]
+    purge_depth: u64, // number of heights below the finalized height whose artifacts are all kept in the pool
+    #[structopt(name = "catch_up_interval", long, default_value = "50")]
+    catch_up_interval: u64, // number of heights between two catch-up packages, 0 to disable them
+    #[structopt(name = "pool_dir", long)]
+    pool_dir: Option<PathBuf>, // directory in which the validated artifacts are persisted, kept in memory only if not given
+    #[structopt(name = "restart", long, requires = "pool_dir")]
+    restart: bool, // restart the replica from the pool persisted in pool_dir and dial the peers it was connected to
 }
 
 #[derive(Clone)]
@@ -93,23 +142,85 @@ pub struct SubnetParams {
     byzantine_nodes_number: u8,
     disagreeing_nodes_number: u8,
     fast_internet_computer_consensus: bool,
+    parent_finalized_rule: bool,
+    ack_policy: AckPolicy,
     artifact_delay: u64,
     artifact_manager_polling_interval: u64,
+    max_batch_size: usize,
+    batch_deadline: u64,
+    wire_encoding: WireEncoding,
+    gossipsub: Option<GossipsubParams>,
     blocksize: usize,
+    purge_depth: u64,
+    catch_up_interval: u64,
+    pool_dir: Option<PathBuf>,
 }
 
 impl SubnetParams {
-    fn new(n: u8, f: u8, p: u8, cod: bool, d: u64, pi: u64, blocksize: usize) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        n: u8,
+        f: u8,
+        p: u8,
+        cod: bool,
+        parent_finalized_rule: bool,
+        ack_policy: AckPolicy,
+        d: u64,
+        pi: u64,
+        max_batch_size: usize,
+        batch_deadline: u64,
+        wire_encoding: WireEncoding,
+        gossipsub: Option<GossipsubParams>,
+        blocksize: usize,
+        purge_depth: u64,
+        catch_up_interval: u64,
+        pool_dir: Option<PathBuf>,
+    ) -> Self {
         Self {
             total_nodes_number: n,
             byzantine_nodes_number: f,
             disagreeing_nodes_number: p,
             fast_internet_computer_consensus: cod,
+            parent_finalized_rule,
+            ack_policy,
             artifact_delay: d,
             artifact_manager_polling_interval: pi,
+            max_batch_size,
+            batch_deadline,
+            wire_encoding,
+            gossipsub,
             blocksize,
+            purge_depth,
+            catch_up_interval,
+            pool_dir,
         }
     }
+
+    /// Return the number of distinct signers required to aggregate shares into
+    /// a notarization or a finalization.
+    pub fn quorum(&self) -> usize {
+        (self.total_nodes_number as usize + self.byzantine_nodes_number as usize) / 2 + 1
+    }
+
+    /// Return the number of distinct acknowledgements required to FP-finalize
+    /// a block.
+    pub fn fp_finalization_threshold(&self) -> usize {
+        (self.total_nodes_number - self.disagreeing_nodes_number) as usize
+    }
+
+    /// Return true if a block can only be FP-finalized once its parent is
+    /// finalized. The first child ack policy is only accepted together with
+    /// this rule, as honest replicas then acknowledge children of different
+    /// parents at the same height, which could otherwise both be FP-finalized.
+    pub fn fp_finalization_requires_finalized_parent(&self) -> bool {
+        self.parent_finalized_rule
+    }
+
+    /// Return the number of random beacon shares required to construct a
+    /// random beacon, such that at least one of them is from an honest replica.
+    pub fn random_beacon_threshold(&self) -> usize {
+        self.byzantine_nodes_number as usize + 1
+    }
 }
 
 async fn get_local_peer_id(req: Request<String>) -> Result {
@@ -133,29 +244,127 @@ async fn post_remote_peers_addresses(
     Ok(res)
 }
 
+async fn get_equivocation_evidence(
+    _req: Request<String>,
+    equivocation_evidence: Arc<RwLock<Vec<EquivocationEvidence>>>,
+) -> Result {
+    let evidence = equivocation_evidence.read().unwrap().clone();
+    let res = Response::builder(200)
+        .header("Content-Type", "application/json")
+        .body(Body::from_json(&evidence)?)
+        .build();
+    Ok(res)
+}
+
 #[async_std::main]
 async fn main() -> Result<()> {
     let opt = Opt::from_args();
-    println!("Replica:{}, blocksize:{}, FICC:{}, f:{}, p:{}, notar_delay:{}, broadcast_interval:{}, and art_man poll interval:{}", opt.r, opt.blocksize, opt.cod, opt.f, opt.p, opt.d, opt.broadcast_interval, opt.artifact_manager_polling_interval);
+    // with the first child ack policy, two blocks at the same height could be FP-finalized without the rule
+    if opt.ack_policy == "first_child" && !opt.parent_finalized_rule {
+        clap::Error::with_description(
+            "--ack_policy first_child can only be used with --parent_finalized_rule",
+            clap::ErrorKind::ArgumentConflict,
+        )
+        .exit();
+    }
+    println!("Replica:{}, blocksize:{}, FICC:{}, f:{}, p:{}, notar_delay:{}, broadcast_interval:{}, art_man poll interval:{}, and crypto:{}", opt.r, opt.blocksize, opt.cod, opt.f, opt.p, opt.d, opt.broadcast_interval, opt.artifact_manager_polling_interval, opt.crypto);
+
+    let subnet_params = SubnetParams::new(
+        opt.n,
+        opt.f,
+        opt.p,
+        opt.cod,
+        opt.parent_finalized_rule,
+        if opt.ack_policy == "first_child" {
+            AckPolicy::FirstChild
+        } else {
+            AckPolicy::FirstBlockPerHeight
+        },
+        opt.d,
+        opt.artifact_manager_polling_interval,
+        opt.max_batch_size,
+        opt.batch_deadline,
+        if opt.wire_encoding == "json" {
+            WireEncoding::Json
+        } else {
+            WireEncoding::Binary
+        },
+        if opt.gossipsub {
+            Some(GossipsubParams::new(
+                opt.mesh_n,
+                opt.mesh_n_low,
+                opt.mesh_n_high,
+                opt.gossip_lazy,
+                opt.heartbeat_interval,
+            ))
+        } else {
+            None
+        },
+        opt.blocksize,
+        opt.purge_depth,
+        opt.catch_up_interval,
+        opt.pool_dir.clone(),
+    );
+
+    if opt.generate_subnet_keys {
+        generate_subnet_keys(&opt.key_dir, opt.n, subnet_params.random_beacon_threshold());
+        println!(
+            "Generated key material of {} replicas in {:?}",
+            opt.n, opt.key_dir
+        );
+        return Ok(());
+    }
+
+    // the keys of the replicas are generated randomly once, so a replica cannot start without them
+    let membership = Membership::load(&opt.key_dir).unwrap_or_else(|| {
+        panic!(
+            "no membership file in {:?}, generate the keys of the subnet with --generate_subnet_keys",
+            opt.key_dir
+        )
+    });
+    let local_key = load_network_key(&opt.key_dir, opt.r);
+    // messages are only accepted from the peers of the subnet, whose peer IDs authenticate their connections
+    let subnet_peers = membership.peer_ids();
+    assert_eq!(
+        subnet_peers.get(&PeerId::from(local_key.public())),
+        Some(&opt.r),
+        "network key of replica {} does not match the membership of the subnet",
+        opt.r
+    );
+    let crypto: Arc<dyn CryptoProvider> = if opt.crypto == "fake" {
+        Arc::new(FakeCryptoProvider::new(
+            opt.r,
+            opt.n,
+            subnet_params.random_beacon_threshold(),
+        ))
+    } else {
+        Arc::new(KeyStore::load(
+            &opt.key_dir,
+            opt.r,
+            &membership,
+            subnet_params.random_beacon_threshold(),
+        ))
+    };
 
     let finalizations_times =
         Arc::new(RwLock::new(BTreeMap::<Height, Option<HeightMetrics>>::new()));
     let cloned_finalization_times = Arc::clone(&finalizations_times);
+    let invalid_artifacts = Arc::new(RwLock::new(InvalidArtifactCounts::new()));
+    let cloned_invalid_artifacts = Arc::clone(&invalid_artifacts);
+    let equivocation_evidence = Arc::new(RwLock::new(Vec::<EquivocationEvidence>::new()));
+    let cloned_equivocation_evidence = Arc::clone(&equivocation_evidence);
 
     let mut my_peer = Peer::new(
         opt.r,
+        local_key,
+        crypto,
         opt.port,
-        SubnetParams::new(
-            opt.n,
-            opt.f,
-            opt.p,
-            opt.cod,
-            opt.d,
-            opt.artifact_manager_polling_interval,
-            opt.blocksize,
-        ),
+        subnet_params,
+        subnet_peers,
         "gossip_blocks",
         cloned_finalization_times,
+        cloned_invalid_artifacts,
+        Arc::clone(&equivocation_evidence),
     )
     .await;
 
@@ -166,6 +375,22 @@ async fn main() -> Result<()> {
     let (sender_peers_addresses, receiver_peers_addresses) =
         crossbeam_channel::unbounded::<String>();
 
+    // a restarted replica does not wait for the addresses of its peers, which it saved when it first joined the subnet
+    let peers_addresses_path = opt
+        .pool_dir
+        .as_ref()
+        .map(|pool_dir| pool_dir.join(format!("replica_{}_peers_addresses.txt", opt.r)));
+    if opt.restart {
+        let peers_addresses_path = peers_addresses_path.as_ref().unwrap();
+        match std::fs::read_to_string(peers_addresses_path) {
+            Ok(addresses) => sender_peers_addresses.send(addresses).unwrap(),
+            Err(_) => println!(
+                "No peers addresses in {:?}, waiting to receive them",
+                peers_addresses_path
+            ),
+        }
+    }
+
     thread::spawn(move || {
         let mut peers_addresses = String::new();
         println!("Waiting to receive peers addresses...");
@@ -173,9 +398,19 @@ async fn main() -> Result<()> {
             peers_addresses.push_str(&addresses);
         }
         println!("Received peers addresses: {}", peers_addresses);
+        if let Some(peers_addresses_path) = peers_addresses_path {
+            std::fs::create_dir_all(peers_addresses_path.parent().unwrap())
+                .expect("can create pool directory");
+            std::fs::write(&peers_addresses_path, &peers_addresses)
+                .expect("can save peers addresses");
+        }
 
         task::block_on(async {
             my_peer.dial_peers(peers_addresses);
+            if opt.restart {
+                // the other replicas are already running, so there is no need to wait for connections to all of them
+                my_peer.start_artifact_manager();
+            }
 
             let starting_time = system_time_now();
             let relative_duration = Duration::from_millis(opt.t * 1000);
@@ -192,9 +427,10 @@ async fn main() -> Result<()> {
                             // if any locally generated artifact, broadcast it
                             if my_peer.artifact_manager_started() {
                                 my_peer.broadcast_message();
+                                my_peer.request_missing_artifacts();
                             }
                         },
-                        event = my_peer.get_next_event() => my_peer.match_event(event),
+                        _ = my_peer.handle_next_event().fuse() => (),
                     }
                     if system_time_now() > initation_end_time {
                         initation_phase = false;
@@ -208,14 +444,18 @@ async fn main() -> Result<()> {
                             // if any locally generated artifact, broadcast it
                             if my_peer.artifact_manager_started() {
                                 my_peer.broadcast_message();
+                                my_peer.request_missing_artifacts();
                             }
                         },
-                        event = my_peer.get_next_event() => my_peer.match_event(event),
+                        _ = my_peer.handle_next_event().fuse() => (),
                     }
                 } else {
                     // println!("\nStopped replica");
                     let benchmark_result = BenchmarkResult {
+                        parent_finalized_rule: opt.parent_finalized_rule,
+                        ack_policy: opt.ack_policy.clone(),
                         finalization_times: finalizations_times.read().unwrap().clone(),
+                        invalid_artifacts: invalid_artifacts.read().unwrap().clone(),
                     };
 
                     let encoded = to_string(&benchmark_result).unwrap();
@@ -242,6 +482,9 @@ async fn main() -> Result<()> {
         post_remote_peers_addresses(req, Arc::clone(&cloned_arc_sender_peers_addresses))
     });
 
+    app.at("/equivocation_evidence")
+        .get(move |req| get_equivocation_evidence(req, Arc::clone(&cloned_equivocation_evidence)));
+
     app.listen(format!("0.0.0.0:{}", opt.port + 1)).await?;
 
     Ok(())
@@ -1,17 +1,41 @@
+use async_std::task;
+use async_trait::async_trait;
+use bincode::Options;
 use crossbeam_channel::{Receiver, Sender};
-use futures::{prelude::stream::StreamExt, stream::SelectNextSome};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    future::{self, FutureExt},
+    io::{AsyncRead, AsyncWrite},
+    prelude::stream::StreamExt,
+    select,
+};
 use libp2p::{
+    core::{
+        upgrade::{read_length_prefixed, write_length_prefixed},
+        ProtocolName,
+    },
     floodsub::{Floodsub, FloodsubEvent, Topic},
+    gossipsub::{
+        Gossipsub, GossipsubConfigBuilder, GossipsubEvent, GossipsubMessage, IdentTopic,
+        MessageAuthenticity, MessageId, ValidationMode,
+    },
     identity::Keypair,
     multiaddr::Protocol,
     multihash::Multihash,
-    swarm::SwarmEvent,
+    request_response::{
+        ProtocolSupport, RequestId, RequestResponse, RequestResponseCodec, RequestResponseConfig,
+        RequestResponseEvent, RequestResponseMessage,
+    },
+    swarm::{behaviour::toggle::Toggle, SwarmEvent},
     Multiaddr, NetworkBehaviour, PeerId, Swarm,
 };
-use serde::{Deserialize, Serialize};
-use std::thread::sleep;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
-    collections::{BTreeMap, BTreeSet},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io, iter,
+    marker::PhantomData,
+    mem,
     sync::{Arc, RwLock},
     time::Duration,
 };
@@ -19,15 +43,19 @@ use std::{
 use crate::{
     artifact_manager::ArtifactProcessorManager,
     consensus_layer::{
-        artifacts::{ConsensusMessage, UnvalidatedArtifact},
+        artifacts::{
+            ConsensusMessage, ConsensusMessageHashable, ConsensusMessageId, InvalidArtifactCounts,
+            UnvalidatedArtifact, UNKNOWN_SENDER,
+        },
         consensus_subcomponents::{
-            block_maker::{Block, Payload},
-            notary::NotarizationShareContent,
+            block_maker::Payload, catch_up_package_maker::CatchUpPackage,
+            validator::EquivocationEvidence,
         },
         height_index::Height,
+        payload_store::{max_chunk_count, PayloadChunk, PayloadStore, PAYLOAD_CHUNK_SIZE},
     },
-    crypto::{Hashed, Signed},
-    time_source::system_time_now,
+    crypto::{CryptoHash, CryptoProvider},
+    time_source::{system_time_now, Time},
     HeightMetrics, SubnetParams,
 };
 
@@ -36,13 +64,24 @@ use crate::{
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "OutEvent")]
 pub struct P2PBehaviour {
-    floodsub: Floodsub,
+    // artifacts are broadcast with either floodsub or gossipsub
+    floodsub: Toggle<Floodsub>,
+    gossipsub: Toggle<Gossipsub>,
+    handshake: RequestResponse<HandshakeCodec>,
+    artifact_exchange: RequestResponse<ArtifactExchangeCodec>,
+    payload_exchange: RequestResponse<PayloadExchangeCodec>,
+    catch_up_package_exchange: RequestResponse<CatchUpPackageExchangeCodec>,
 }
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum OutEvent {
     Floodsub(FloodsubEvent),
+    Gossipsub(GossipsubEvent),
+    Handshake(RequestResponseEvent<Handshake, Handshake>),
+    ArtifactExchange(RequestResponseEvent<ConsensusMessageId, Vec<ConsensusMessage>>),
+    PayloadExchange(RequestResponseEvent<PayloadChunkRequest, Option<PayloadChunk>>),
+    CatchUpPackageExchange(RequestResponseEvent<CatchUpPackage, ()>),
 }
 
 impl From<FloodsubEvent> for OutEvent {
@@ -51,9 +90,416 @@ impl From<FloodsubEvent> for OutEvent {
     }
 }
 
+impl From<GossipsubEvent> for OutEvent {
+    fn from(v: GossipsubEvent) -> Self {
+        Self::Gossipsub(v)
+    }
+}
+
+impl From<RequestResponseEvent<Handshake, Handshake>> for OutEvent {
+    fn from(v: RequestResponseEvent<Handshake, Handshake>) -> Self {
+        Self::Handshake(v)
+    }
+}
+
+impl From<RequestResponseEvent<ConsensusMessageId, Vec<ConsensusMessage>>> for OutEvent {
+    fn from(v: RequestResponseEvent<ConsensusMessageId, Vec<ConsensusMessage>>) -> Self {
+        Self::ArtifactExchange(v)
+    }
+}
+
+impl From<RequestResponseEvent<PayloadChunkRequest, Option<PayloadChunk>>> for OutEvent {
+    fn from(v: RequestResponseEvent<PayloadChunkRequest, Option<PayloadChunk>>) -> Self {
+        Self::PayloadExchange(v)
+    }
+}
+
+impl From<RequestResponseEvent<CatchUpPackage, ()>> for OutEvent {
+    fn from(v: RequestResponseEvent<CatchUpPackage, ()>) -> Self {
+        Self::CatchUpPackageExchange(v)
+    }
+}
+
+// Maximum size of a request or response of the request-response protocols
+const MAX_MESSAGE_SIZE: usize = 10_000_000;
+
+/// Version of the encoding of the network messages. It is the first byte of
+/// every message and is exchanged in the handshake, so it must be increased
+/// whenever [Message] or the artifacts it carries change.
+const WIRE_PROTOCOL_VERSION: u8 = 3;
+
+/// Encoding of the network messages. Binary is compact and fast to parse, JSON
+/// is meant for debugging, as messages can then be read in packet captures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireEncoding {
+    Binary,
+    Json,
+}
+
+impl WireEncoding {
+    fn tag(&self) -> u8 {
+        match self {
+            WireEncoding::Binary => 0,
+            WireEncoding::Json => 1,
+        }
+    }
+
+    /// Return the bytes of the value in this encoding, prefixed by the
+    /// version of the wire protocol.
+    pub fn encode<S: Serialize>(&self, value: &S) -> Vec<u8> {
+        let mut bytes = vec![WIRE_PROTOCOL_VERSION];
+        match self {
+            WireEncoding::Binary => bincode_options()
+                .serialize_into(&mut bytes, value)
+                .expect("can serialize network message"),
+            WireEncoding::Json => {
+                serde_json::to_writer(&mut bytes, value).expect("can serialize network message")
+            }
+        }
+        bytes
+    }
+
+    /// Parse bytes produced by [WireEncoding::encode], which fails if they
+    /// were produced by a different version of the wire protocol.
+    pub fn decode<D: DeserializeOwned>(&self, bytes: &[u8]) -> Result<D, String> {
+        match bytes.split_first() {
+            Some((&WIRE_PROTOCOL_VERSION, payload)) => match self {
+                WireEncoding::Binary => bincode_options()
+                    .deserialize(payload)
+                    .map_err(|err| err.to_string()),
+                WireEncoding::Json => {
+                    serde_json::from_slice(payload).map_err(|err| err.to_string())
+                }
+            },
+            Some((version, _)) => Err(format!("unsupported wire protocol version {}", version)),
+            None => Err("empty message".to_string()),
+        }
+    }
+}
+
+// the limit prevents a malformed length from allocating more than a message can hold
+fn bincode_options() -> impl Options {
+    bincode::options().with_limit(MAX_MESSAGE_SIZE as u64)
+}
+
+/// Parameters of the gossipsub mesh, used to broadcast artifacts instead of
+/// floodsub when given.
+#[derive(Clone, Debug)]
+pub struct GossipsubParams {
+    mesh_n: usize,
+    mesh_n_low: usize,
+    mesh_n_high: usize,
+    gossip_lazy: usize,
+    heartbeat_interval: u64,
+}
+
+impl GossipsubParams {
+    pub fn new(
+        mesh_n: usize,
+        mesh_n_low: usize,
+        mesh_n_high: usize,
+        gossip_lazy: usize,
+        heartbeat_interval: u64,
+    ) -> Self {
+        Self {
+            mesh_n,
+            mesh_n_low,
+            mesh_n_high,
+            gossip_lazy,
+            heartbeat_interval,
+        }
+    }
+
+    // gossipsub requires at most half of the mesh to be outbound connections
+    fn mesh_outbound_min(&self) -> usize {
+        self.mesh_n_low.min(self.mesh_n / 2).min(2)
+    }
+}
+
+fn new_gossipsub(
+    local_peer_id: PeerId,
+    params: &GossipsubParams,
+    encoding: WireEncoding,
+) -> Gossipsub {
+    let config = GossipsubConfigBuilder::default()
+        .mesh_n(params.mesh_n)
+        .mesh_n_low(params.mesh_n_low)
+        .mesh_n_high(params.mesh_n_high)
+        .mesh_outbound_min(params.mesh_outbound_min())
+        .gossip_lazy(params.gossip_lazy)
+        .heartbeat_interval(Duration::from_millis(params.heartbeat_interval))
+        .message_id_fn(move |message| artifact_message_id(encoding, message))
+        // artifacts are signed by the replicas and are attributed to the authenticated peer which forwarded them,
+        // so messages are not signed again
+        .validation_mode(ValidationMode::Permissive)
+        .build()
+        .expect("valid gossipsub parameters");
+    Gossipsub::new(MessageAuthenticity::Author(local_peer_id), config)
+        .expect("can create gossipsub behaviour")
+}
+
+/// Return the identifier of a gossipsub message from the hashes of the
+/// artifacts it holds, so that the same artifacts published twice, e.g. by
+/// different replicas or in different batches, are only delivered once.
+fn artifact_message_id(encoding: WireEncoding, message: &GossipsubMessage) -> MessageId {
+    let hashes: Vec<_> = match encoding.decode::<Message>(&message.data) {
+        Ok(Message::ConsensusMessage(artifact)) => vec![artifact.get_cm_hash()],
+        Ok(Message::ConsensusMessageBatch(artifacts)) => artifacts
+            .iter()
+            .map(|artifact| artifact.get_cm_hash())
+            .collect(),
+        // messages which cannot be parsed are dropped when delivered
+        _ => return MessageId::new(&Sha256::digest(&message.data)),
+    };
+    MessageId::new(&Sha256::digest(&encoding.encode(&hashes)))
+}
+
+/// Handshake exchanged with every peer upon connection. Replicas refuse the
+/// peers with a different wire protocol version or encoding, whose messages
+/// they could not parse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    version: u8,
+    encoding: u8,
+}
+
+impl Handshake {
+    fn new(encoding: WireEncoding) -> Self {
+        Self {
+            version: WIRE_PROTOCOL_VERSION,
+            encoding: encoding.tag(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HandshakeProtocol;
+
+impl ProtocolName for HandshakeProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/handshake/1"
+    }
+}
+
+// the handshake does not depend on the encoding, so that peers with any encoding understand it
+#[derive(Clone)]
+pub struct HandshakeCodec;
+
+async fn read_handshake<T>(io: &mut T) -> io::Result<Handshake>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    match read_length_prefixed(io, 2).await?[..] {
+        [version, encoding] => Ok(Handshake { version, encoding }),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "malformed handshake",
+        )),
+    }
+}
+
+async fn write_handshake<T>(io: &mut T, handshake: Handshake) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    write_length_prefixed(io, [handshake.version, handshake.encoding]).await
+}
+
+#[async_trait]
+impl RequestResponseCodec for HandshakeCodec {
+    type Protocol = HandshakeProtocol;
+    type Request = Handshake;
+    type Response = Handshake;
+
+    async fn read_request<T>(&mut self, _: &HandshakeProtocol, io: &mut T) -> io::Result<Handshake>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_handshake(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &HandshakeProtocol, io: &mut T) -> io::Result<Handshake>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_handshake(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &HandshakeProtocol,
+        io: &mut T,
+        request: Handshake,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_handshake(io, request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &HandshakeProtocol,
+        io: &mut T,
+        response: Handshake,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_handshake(io, response).await
+    }
+}
+
+/// Protocol with which a replica requests from its peers the artifacts it is
+/// missing, which the peers answer from their validated pool.
+#[derive(Debug, Clone)]
+pub struct ArtifactExchangeProtocol;
+
+impl ProtocolName for ArtifactExchangeProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/artifact-exchange/1"
+    }
+}
+
+/// Protocol with which a replica downloads the payload of a block proposal
+/// from its peers, one chunk at a time.
+#[derive(Debug, Clone)]
+pub struct PayloadExchangeProtocol;
+
+impl ProtocolName for PayloadExchangeProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/payload-exchange/1"
+    }
+}
+
+/// Protocol with which a replica sends its latest catch-up package to a peer
+/// which subscribed to it, e.g. after restarting. Unlike a published message,
+/// it reaches the peer even if the same catch-up package was published before.
+#[derive(Debug, Clone)]
+pub struct CatchUpPackageExchangeProtocol;
+
+impl ProtocolName for CatchUpPackageExchangeProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/catch-up-package-exchange/1"
+    }
+}
+
+/// Request for the chunk with the given index of the payload with the given hash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PayloadChunkRequest {
+    payload_hash: CryptoHash,
+    index: usize,
+}
+
+/// Codec of the request-response protocols, whose requests and responses are
+/// encoded like the other network messages.
+pub struct WireCodec<P, Req, Resp> {
+    encoding: WireEncoding,
+    messages: PhantomData<(P, Req, Resp)>,
+}
+
+impl<P, Req, Resp> WireCodec<P, Req, Resp> {
+    fn new(encoding: WireEncoding) -> Self {
+        Self {
+            encoding,
+            messages: PhantomData,
+        }
+    }
+
+    async fn read<T, D>(&self, io: &mut T) -> io::Result<D>
+    where
+        T: AsyncRead + Unpin + Send,
+        D: DeserializeOwned,
+    {
+        let bytes = read_length_prefixed(io, MAX_MESSAGE_SIZE).await?;
+        self.encoding
+            .decode(&bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    async fn write<T, S>(&self, io: &mut T, value: &S) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+        S: Serialize,
+    {
+        write_length_prefixed(io, self.encoding.encode(value)).await
+    }
+}
+
+impl<P, Req, Resp> Clone for WireCodec<P, Req, Resp> {
+    fn clone(&self) -> Self {
+        Self::new(self.encoding)
+    }
+}
+
+type ArtifactExchangeCodec =
+    WireCodec<ArtifactExchangeProtocol, ConsensusMessageId, Vec<ConsensusMessage>>;
+type PayloadExchangeCodec =
+    WireCodec<PayloadExchangeProtocol, PayloadChunkRequest, Option<PayloadChunk>>;
+type CatchUpPackageExchangeCodec = WireCodec<CatchUpPackageExchangeProtocol, CatchUpPackage, ()>;
+
+#[async_trait]
+impl<P, Req, Resp> RequestResponseCodec for WireCodec<P, Req, Resp>
+where
+    P: ProtocolName + Send + Sync + Clone,
+    Req: Serialize + DeserializeOwned + Send + Sync,
+    Resp: Serialize + DeserializeOwned + Send + Sync,
+{
+    type Protocol = P;
+    type Request = Req;
+    type Response = Resp;
+
+    async fn read_request<T>(&mut self, _: &P, io: &mut T) -> io::Result<Req>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        self.read::<T, Req>(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &P, io: &mut T) -> io::Result<Resp>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        self.read::<T, Resp>(io).await
+    }
+
+    async fn write_request<T>(&mut self, _: &P, io: &mut T, request: Req) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        self.write(io, &request).await
+    }
+
+    async fn write_response<T>(&mut self, _: &P, io: &mut T, response: Resp) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        self.write(io, &response).await
+    }
+}
+
+/// Download of the payload of a block proposal. The chunks are requested from
+/// one peer at a time, starting with the peer from which the proposal was
+/// received, and from the next peer if it fails to provide them.
+struct PayloadDownload {
+    height: Height,
+    peers: Vec<PeerId>,
+    peer_index: usize,
+    // empty until the number of chunks is known from the first chunk received
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+impl PayloadDownload {
+    fn peer(&self) -> Option<PeerId> {
+        self.peers.get(self.peer_index).copied()
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
     ConsensusMessage(ConsensusMessage),
+    ConsensusMessageBatch(Vec<ConsensusMessage>),
     KeepAliveMessage,
 }
 
@@ -62,38 +508,80 @@ pub struct Peer {
     pub id: PeerId,
     artifact_manager_started: bool,
     subnet_params: SubnetParams,
+    crypto: Arc<dyn CryptoProvider>,
     floodsub_topic: Topic,
+    gossipsub_topic: IdentTopic,
     swarm: Swarm<P2PBehaviour>,
     listening_port: u64,
+    // replica number of each peer of the subnet, from which alone messages are accepted
+    subnet_peers: BTreeMap<PeerId, u8>,
     subscribed_peers: BTreeSet<PeerId>,
     connected_peers: BTreeSet<PeerId>,
-    receiver_outgoing_artifact: Receiver<ConsensusMessage>,
-    sender_outgoing_artifact: Sender<ConsensusMessage>,
+    // peers whose handshake matched ours, and peers refused because it did not
+    compatible_peers: BTreeSet<PeerId>,
+    refused_peers: BTreeSet<PeerId>,
+    receiver_outgoing_artifact: UnboundedReceiver<ConsensusMessage>,
+    sender_outgoing_artifact: UnboundedSender<ConsensusMessage>,
+    // locally generated artifacts waiting to be published, and the time until which they may wait
+    outgoing_batch: Vec<ConsensusMessage>,
+    outgoing_batch_deadline: Option<Time>,
+    receiver_artifact_request: Receiver<ConsensusMessageId>,
+    sender_artifact_request: Sender<ConsensusMessageId>,
     finalization_times: Arc<RwLock<BTreeMap<Height, Option<HeightMetrics>>>>,
+    invalid_artifacts: Arc<RwLock<InvalidArtifactCounts>>,
+    equivocation_evidence: Arc<RwLock<Vec<EquivocationEvidence>>>,
     manager: Option<ArtifactProcessorManager>,
+    latest_catch_up_package: Option<CatchUpPackage>,
+    payloads: Arc<RwLock<PayloadStore>>,
+    // downloads of the payloads of the received proposals, by payload hash
+    payload_downloads: BTreeMap<CryptoHash, PayloadDownload>,
+    chunk_requests: HashMap<RequestId, (CryptoHash, PeerId)>,
 }
 
 impl Peer {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         replica_number: u8,
+        local_key: Keypair,
+        crypto: Arc<dyn CryptoProvider>,
         listening_port: u64,
         subnet_params: SubnetParams,
+        subnet_peers: BTreeMap<PeerId, u8>,
         topic: &str,
         finalization_times: Arc<RwLock<BTreeMap<Height, Option<HeightMetrics>>>>,
+        invalid_artifacts: Arc<RwLock<InvalidArtifactCounts>>,
+        equivocation_evidence: Arc<RwLock<Vec<EquivocationEvidence>>>,
     ) -> Self {
-        // Create a random PeerId
-        let local_key = Keypair::generate_ed25519();
         let local_peer_id = PeerId::from(local_key.public());
+        // the payloads are persisted next to the pool, as the reloaded proposals refer to them
+        let payloads = match &subnet_params.pool_dir {
+            Some(pool_dir) => PayloadStore::open(
+                &pool_dir.join(format!("replica_{}_payloads", subnet_peers[&local_peer_id])),
+            ),
+            None => PayloadStore::new(),
+        };
+
+        let gossipsub = subnet_params
+            .gossipsub
+            .as_ref()
+            .map(|params| new_gossipsub(local_peer_id, params, subnet_params.wire_encoding));
 
         // Set up an encrypted DNS-enabled TCP Transport
         let transport = libp2p::development_transport(local_key).await.unwrap();
 
         // Create a Floodsub topic
         let floodsub_topic = Topic::new(topic);
+        let gossipsub_topic = IdentTopic::new(topic);
 
         // channel used to transmit locally generated artifacts from the consensus layer to the network layer so that they can be broadcasted to other peers
         let (sender_outgoing_artifact, receiver_outgoing_artifact) =
-            crossbeam_channel::unbounded::<ConsensusMessage>();
+            mpsc::unbounded::<ConsensusMessage>();
+
+        // channel used to transmit the requests for missing artifacts from the consensus layer to the network layer so that they can be sent to other peers
+        let (sender_artifact_request, receiver_artifact_request) =
+            crossbeam_channel::unbounded::<ConsensusMessageId>();
+
+        let wire_encoding = subnet_params.wire_encoding;
 
         // Create a Swarm to manage peers and events
         Self {
@@ -101,22 +589,68 @@ impl Peer {
             id: local_peer_id,
             artifact_manager_started: false,
             subnet_params,
+            crypto,
             floodsub_topic: floodsub_topic.clone(),
+            gossipsub_topic: gossipsub_topic.clone(),
             swarm: {
                 let mut behaviour = P2PBehaviour {
-                    floodsub: Floodsub::new(local_peer_id),
+                    floodsub: Toggle::from(match gossipsub {
+                        Some(_) => None,
+                        None => Some(Floodsub::new(local_peer_id)),
+                    }),
+                    gossipsub: Toggle::from(gossipsub),
+                    handshake: RequestResponse::new(
+                        HandshakeCodec,
+                        iter::once((HandshakeProtocol, ProtocolSupport::Full)),
+                        RequestResponseConfig::default(),
+                    ),
+                    artifact_exchange: RequestResponse::new(
+                        ArtifactExchangeCodec::new(wire_encoding),
+                        iter::once((ArtifactExchangeProtocol, ProtocolSupport::Full)),
+                        RequestResponseConfig::default(),
+                    ),
+                    payload_exchange: RequestResponse::new(
+                        PayloadExchangeCodec::new(wire_encoding),
+                        iter::once((PayloadExchangeProtocol, ProtocolSupport::Full)),
+                        RequestResponseConfig::default(),
+                    ),
+                    catch_up_package_exchange: RequestResponse::new(
+                        CatchUpPackageExchangeCodec::new(wire_encoding),
+                        iter::once((CatchUpPackageExchangeProtocol, ProtocolSupport::Full)),
+                        RequestResponseConfig::default(),
+                    ),
                 };
 
-                behaviour.floodsub.subscribe(floodsub_topic);
+                if let Some(floodsub) = behaviour.floodsub.as_mut() {
+                    floodsub.subscribe(floodsub_topic);
+                }
+                if let Some(gossipsub) = behaviour.gossipsub.as_mut() {
+                    gossipsub
+                        .subscribe(&gossipsub_topic)
+                        .expect("can subscribe to topic");
+                }
                 Swarm::new(transport, behaviour, local_peer_id)
             },
             listening_port,
+            subnet_peers,
             subscribed_peers: BTreeSet::new(),
             connected_peers: BTreeSet::new(),
+            compatible_peers: BTreeSet::new(),
+            refused_peers: BTreeSet::new(),
             receiver_outgoing_artifact,
             sender_outgoing_artifact,
+            outgoing_batch: vec![],
+            outgoing_batch_deadline: None,
+            receiver_artifact_request,
+            sender_artifact_request,
             finalization_times,
+            invalid_artifacts,
+            equivocation_evidence,
             manager: None,
+            latest_catch_up_package: None,
+            payloads: Arc::new(RwLock::new(payloads)),
+            payload_downloads: BTreeMap::new(),
+            chunk_requests: HashMap::new(),
         }
     }
 
@@ -135,14 +669,21 @@ impl Peer {
             let remote_peer_multiaddr: Multiaddr = peer_address.parse().expect("valid address");
             let remote_peer_id = PeerId::try_from_multiaddr(&remote_peer_multiaddr)
                 .expect("multiaddress with peer ID");
+            if !self.subnet_peers.contains_key(&remote_peer_id) {
+                println!(
+                    "Not dialing peer: {:?} which is not a replica of the subnet",
+                    peer_address
+                );
+                continue;
+            }
             if !self.subscribed_peers.contains(&remote_peer_id) {
                 self.swarm
                     .dial(remote_peer_multiaddr.clone())
                     .expect("known peer");
-                self.swarm
-                    .behaviour_mut()
-                    .floodsub
-                    .add_node_to_partial_view(remote_peer_id);
+                // gossipsub learns the subscriptions of the peers once connected
+                if let Some(floodsub) = self.swarm.behaviour_mut().floodsub.as_mut() {
+                    floodsub.add_node_to_partial_view(remote_peer_id);
+                }
                 self.subscribed_peers.insert(remote_peer_id);
                 println!(
                     "Dialed remote peer: {:?} and added to broadcast list",
@@ -152,82 +693,180 @@ impl Peer {
         }
     }
 
+    /// Publish the locally generated artifacts, packing up to `max_batch_size`
+    /// of them in each network message. Artifacts which do not fill a batch
+    /// are only published once the oldest of them waited for `batch_deadline`.
     pub fn broadcast_message(&mut self) {
-        if let Ok(outgoing_artifact) = self.receiver_outgoing_artifact.try_recv() {
-            if self.replica_number == 1 {
-                match &outgoing_artifact {
-                    ConsensusMessage::BlockProposal(proposal) => {
-                        if proposal.content.value.height == 1 {
-                            sleep(Duration::from_millis(100));
-                        }
-                    }
-                    ConsensusMessage::NotarizationShare(share) => match &share.content {
-                        NotarizationShareContent::COD(ack) => {
-                            if ack.height == 1 {
-                                println!("Rebroadcasting first block proposal");
-                                self.swarm.behaviour_mut().floodsub.publish(
-                                    self.floodsub_topic.clone(),
-                                    serde_json::to_string::<Message>(&Message::ConsensusMessage(
-                                        ConsensusMessage::BlockProposal(Signed {
-                                            content: Hashed {
-                                                hash: String::from("block1"),
-                                                value: Block {
-                                                    parent: String::from("block0"),
-                                                    payload: Payload::new(
-                                                        self.subnet_params.blocksize,
-                                                    ),
-                                                    height: 1,
-                                                    rank: 0,
-                                                },
-                                            },
-                                            signature: self.replica_number,
-                                        }),
-                                    ))
-                                    .unwrap(),
-                                );
-                                println!("Dupsko");
-                            }
-                        }
-                        NotarizationShareContent::ICC(share) => {
-                            if share.height == 1 {
-                                println!("Rebroadcasting first block proposal");
-                                self.swarm.behaviour_mut().floodsub.publish(
-                                    self.floodsub_topic.clone(),
-                                    serde_json::to_string::<Message>(&Message::ConsensusMessage(
-                                        ConsensusMessage::BlockProposal(Signed {
-                                            content: Hashed {
-                                                hash: String::from("block1"),
-                                                value: Block {
-                                                    parent: String::from("block0"),
-                                                    payload: Payload::new(
-                                                        self.subnet_params.blocksize,
-                                                    ),
-                                                    height: 1,
-                                                    rank: 0,
-                                                },
-                                            },
-                                            signature: self.replica_number,
-                                        }),
-                                    ))
-                                    .unwrap(),
-                                );
-                            }
-                        }
-                    },
-                    _ => (),
-                }
+        // the artifacts produced by one round of the consensus layer are queued together
+        while let Ok(Some(outgoing_artifact)) = self.receiver_outgoing_artifact.try_next() {
+            self.queue_outgoing_artifact(outgoing_artifact);
+        }
+        let max_batch_size = self.subnet_params.max_batch_size.max(1);
+        while self.outgoing_batch.len() >= max_batch_size {
+            let rest = self.outgoing_batch.split_off(max_batch_size);
+            let batch = mem::replace(&mut self.outgoing_batch, rest);
+            self.publish_batch(batch);
+        }
+        match self.outgoing_batch_deadline {
+            Some(deadline) if deadline <= system_time_now() => {
+                let batch = mem::take(&mut self.outgoing_batch);
+                self.publish_batch(batch);
             }
-            // println!("\nBroadcasted locally generated artifact: {:?}", outgoing_artifact);
-            self.swarm.behaviour_mut().floodsub.publish(
-                self.floodsub_topic.clone(),
-                serde_json::to_string::<Message>(&Message::ConsensusMessage(outgoing_artifact))
-                    .unwrap(),
-            );
+            _ => (),
+        }
+        if self.outgoing_batch.is_empty() {
+            self.outgoing_batch_deadline = None;
+        }
+    }
+
+    fn queue_outgoing_artifact(&mut self, outgoing_artifact: ConsensusMessage) {
+        if let ConsensusMessage::CatchUpPackage(catch_up_package) = &outgoing_artifact {
+            self.latest_catch_up_package = Some(catch_up_package.clone());
+        }
+        if self.outgoing_batch.is_empty() {
+            self.outgoing_batch_deadline =
+                Some(system_time_now() + Duration::from_millis(self.subnet_params.batch_deadline));
+        }
+        self.outgoing_batch.push(outgoing_artifact);
+    }
+
+    fn publish_batch(&mut self, batch: Vec<ConsensusMessage>) {
+        if batch.is_empty() {
+            return;
         }
+        // println!("\nBroadcasted {} locally generated artifacts", batch.len());
+        self.publish(&Message::ConsensusMessageBatch(batch));
     }
 
-    pub fn get_next_event(&mut self) -> SelectNextSome<'_, Swarm<P2PBehaviour>> {
-        self.swarm.select_next_some()
+    fn publish(&mut self, message: &Message) {
+        let data = self.subnet_params.wire_encoding.encode(message);
+        let behaviour = self.swarm.behaviour_mut();
+        if let Some(floodsub) = behaviour.floodsub.as_mut() {
+            floodsub.publish(self.floodsub_topic.clone(), data);
+        } else if let Some(gossipsub) = behaviour.gossipsub.as_mut() {
+            if let Err(err) = gossipsub.publish(self.gossipsub_topic.clone(), data) {
+                println!("Failed to publish message: {:?}", err);
+            }
+        }
+    }
+
+    /// Handle a message published on the topic and received from the given
+    /// peer. With gossipsub, the source is the connected peer which forwarded
+    /// the message. Floodsub only gives the author claimed by the message,
+    /// which is not authenticated, so its artifacts are not attributed to it.
+    fn on_published_message(&mut self, source: Option<PeerId>, data: &[u8], authenticated: bool) {
+        let source = match source {
+            Some(source) if !self.refused_peers.contains(&source) => source,
+            _ => return,
+        };
+        match self.subnet_params.wire_encoding.decode::<Message>(data) {
+            Ok(message) => self.handle_incoming_message(message, source, authenticated),
+            Err(err) => println!(
+                "Dropped message from peer: {:?} which cannot be parsed: {}",
+                source, err
+            ),
+        }
+    }
+
+    fn on_peer_subscribed(&mut self, remote_peer_id: PeerId) {
+        if self.refused_peers.contains(&remote_peer_id)
+            || !self.subnet_peers.contains_key(&remote_peer_id)
+        {
+            return;
+        }
+        if !self.subscribed_peers.contains(&remote_peer_id) {
+            if let Some(floodsub) = self.swarm.behaviour_mut().floodsub.as_mut() {
+                floodsub.add_node_to_partial_view(remote_peer_id);
+            }
+            self.subscribed_peers.insert(remote_peer_id);
+            println!("Added peer with ID: {:?} to broadcast list", remote_peer_id);
+        }
+        // a replica which (re)joins the subnet late catches up from the latest catch-up package
+        // it is sent directly to the peer, as publishing it again would not reach the peer
+        if let Some(catch_up_package) = self.latest_catch_up_package.clone() {
+            self.swarm
+                .behaviour_mut()
+                .catch_up_package_exchange
+                .send_request(&remote_peer_id, catch_up_package);
+        }
+    }
+
+    /// Refuse a peer whose messages cannot be parsed, so that artifacts are
+    /// not broadcast to it and its messages are dropped. The connection is
+    /// kept, so that the peer receives the handshake response refusing it.
+    fn refuse_peer(&mut self, peer_id: PeerId, handshake: Handshake) {
+        if !self.refused_peers.insert(peer_id) {
+            return;
+        }
+        println!(
+            "Refusing peer: {} with incompatible handshake: {:?}, expected: {:?}",
+            peer_id,
+            handshake,
+            Handshake::new(self.subnet_params.wire_encoding)
+        );
+        let behaviour = self.swarm.behaviour_mut();
+        if let Some(floodsub) = behaviour.floodsub.as_mut() {
+            floodsub.remove_node_from_partial_view(&peer_id);
+        }
+        if let Some(gossipsub) = behaviour.gossipsub.as_mut() {
+            gossipsub.blacklist_peer(&peer_id);
+        }
+        self.subscribed_peers.remove(&peer_id);
+        self.compatible_peers.remove(&peer_id);
+    }
+
+    fn on_handshake(&mut self, peer_id: PeerId, handshake: Handshake) {
+        if handshake != Handshake::new(self.subnet_params.wire_encoding) {
+            self.refuse_peer(peer_id, handshake);
+        } else if !self.refused_peers.contains(&peer_id) {
+            self.compatible_peers.insert(peer_id);
+        }
+        // refused peers do not hold back the others, as consensus tolerates them like faulty replicas
+        if self.compatible_peers.len() + self.refused_peers.len()
+            == (self.subnet_params.total_nodes_number - 1) as usize
+        {
+            self.start_artifact_manager();
+        }
+    }
+
+    /// Ask the peers for the artifacts which the consensus layer is missing.
+    pub fn request_missing_artifacts(&mut self) {
+        for request in self.receiver_artifact_request.try_iter() {
+            // println!("\nRequesting missing artifact: {:?}", request);
+            for peer_id in self.subscribed_peers.iter() {
+                self.swarm
+                    .behaviour_mut()
+                    .artifact_exchange
+                    .send_request(peer_id, request.clone());
+            }
+        }
+    }
+
+    /// Wait for the next swarm event or locally generated artifact, or for the
+    /// deadline of the pending batch, and handle it. Locally generated
+    /// artifacts are thus published as soon as they are produced, instead of
+    /// at the next broadcast tick.
+    pub async fn handle_next_event(&mut self) {
+        let batch_deadline = self.outgoing_batch_deadline;
+        let flush = async move {
+            match batch_deadline {
+                Some(deadline) => {
+                    let now = system_time_now();
+                    if deadline > now {
+                        task::sleep(deadline - now).await;
+                    }
+                }
+                None => future::pending::<()>().await,
+            }
+        };
+        select! {
+            event = self.swarm.select_next_some() => self.match_event(event),
+            outgoing_artifact = self.receiver_outgoing_artifact.select_next_some() => {
+                self.queue_outgoing_artifact(outgoing_artifact);
+                self.broadcast_message();
+            },
+            _ = flush.fuse() => self.broadcast_message(),
+        }
     }
 
     pub fn match_event<T>(&mut self, event: SwarmEvent<OutEvent, T>) {
@@ -240,50 +879,150 @@ impl Peer {
                 // println!("Local peer ID: {:?}", self.id);
             }
             SwarmEvent::Behaviour(OutEvent::Floodsub(floodsub_event)) => match floodsub_event {
-                FloodsubEvent::Message(floodsub_message) => {
-                    let floodsub_content = String::from_utf8_lossy(&floodsub_message.data);
-                    let message = serde_json::from_str::<Message>(&floodsub_content)
-                        .expect("can parse artifact");
-                    self.handle_incoming_message(message);
-                }
+                FloodsubEvent::Message(floodsub_message) => self.on_published_message(
+                    Some(floodsub_message.source),
+                    &floodsub_message.data,
+                    false,
+                ),
                 FloodsubEvent::Subscribed {
                     peer_id: remote_peer_id,
                     ..
+                } => self.on_peer_subscribed(remote_peer_id),
+                _ => println!("Unhandled floodsub event"),
+            },
+            SwarmEvent::Behaviour(OutEvent::Gossipsub(gossipsub_event)) => match gossipsub_event {
+                GossipsubEvent::Message {
+                    propagation_source,
+                    message,
+                    ..
+                } => self.on_published_message(Some(propagation_source), &message.data, true),
+                GossipsubEvent::Subscribed {
+                    peer_id: remote_peer_id,
+                    ..
+                } => self.on_peer_subscribed(remote_peer_id),
+                _ => println!("Unhandled gossipsub event"),
+            },
+            SwarmEvent::Behaviour(OutEvent::Handshake(RequestResponseEvent::Message {
+                peer,
+                message,
+            })) => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
                 } => {
-                    if !self.subscribed_peers.contains(&remote_peer_id) {
-                        self.swarm
-                            .behaviour_mut()
-                            .floodsub
-                            .add_node_to_partial_view(remote_peer_id);
-                        self.subscribed_peers.insert(remote_peer_id);
-                        println!("Added peer with ID: {:?} to broadcast list", remote_peer_id);
-                    }
+                    let _ = self
+                        .swarm
+                        .behaviour_mut()
+                        .handshake
+                        .send_response(channel, Handshake::new(self.subnet_params.wire_encoding));
+                    self.on_handshake(peer, request);
+                }
+                RequestResponseMessage::Response { response, .. } => {
+                    self.on_handshake(peer, response)
+                }
+            },
+            // the handshake is sent again when the connection is established again
+            SwarmEvent::Behaviour(OutEvent::Handshake(_)) => (),
+            SwarmEvent::Behaviour(OutEvent::ArtifactExchange(RequestResponseEvent::Message {
+                peer,
+                message,
+            })) => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    let artifacts = match &self.manager {
+                        Some(manager) => manager.on_artifact_request(&request),
+                        None => vec![],
+                    };
+                    // the request fails if the peer is not connected anymore, in which case it asks again
+                    let _ = self
+                        .swarm
+                        .behaviour_mut()
+                        .artifact_exchange
+                        .send_response(channel, artifacts);
+                }
+                RequestResponseMessage::Response { response, .. } => {
+                    self.handle_incoming_message(
+                        Message::ConsensusMessageBatch(response),
+                        peer,
+                        true,
+                    );
                 }
-                _ => println!("Unhandled floodsub event"),
             },
+            // failed requests are sent again by the validator if the artifact is still missing
+            SwarmEvent::Behaviour(OutEvent::ArtifactExchange(_)) => (),
+            SwarmEvent::Behaviour(OutEvent::PayloadExchange(RequestResponseEvent::Message {
+                message,
+                ..
+            })) => match message {
+                RequestResponseMessage::Request {
+                    request, channel, ..
+                } => {
+                    let chunk = self
+                        .payloads
+                        .read()
+                        .unwrap()
+                        .get_chunk(&request.payload_hash, request.index);
+                    let _ = self
+                        .swarm
+                        .behaviour_mut()
+                        .payload_exchange
+                        .send_response(channel, chunk);
+                }
+                RequestResponseMessage::Response {
+                    request_id,
+                    response,
+                } => self.on_payload_chunk(request_id, response),
+            },
+            SwarmEvent::Behaviour(OutEvent::PayloadExchange(
+                RequestResponseEvent::OutboundFailure { request_id, .. },
+            )) => self.on_payload_chunk(request_id, None),
+            SwarmEvent::Behaviour(OutEvent::PayloadExchange(_)) => (),
+            SwarmEvent::Behaviour(OutEvent::CatchUpPackageExchange(
+                RequestResponseEvent::Message {
+                    peer,
+                    message:
+                        RequestResponseMessage::Request {
+                            request, channel, ..
+                        },
+                },
+            )) => {
+                let _ = self
+                    .swarm
+                    .behaviour_mut()
+                    .catch_up_package_exchange
+                    .send_response(channel, ());
+                self.handle_incoming_message(
+                    Message::ConsensusMessage(ConsensusMessage::CatchUpPackage(request)),
+                    peer,
+                    true,
+                );
+            }
+            // the catch-up package is sent again when the peer subscribes again
+            SwarmEvent::Behaviour(OutEvent::CatchUpPackageExchange(_)) => (),
             SwarmEvent::ConnectionEstablished {
                 peer_id: remote_peer_id,
                 ..
             } => {
+                if !self.subnet_peers.contains_key(&remote_peer_id) {
+                    println!(
+                        "Rejecting connection with peer: {} which is not a replica of the subnet",
+                        remote_peer_id
+                    );
+                    let _ = self.swarm.disconnect_peer_id(remote_peer_id);
+                    return;
+                }
                 if !self.connected_peers.contains(&remote_peer_id) {
                     println!(
                         "Connection established with remote peer: {:?}",
                         remote_peer_id
                     );
                     self.connected_peers.insert(remote_peer_id);
-                    if self.connected_peers.len()
-                        == (self.subnet_params.total_nodes_number - 1) as usize
-                    {
-                        self.manager = Some(ArtifactProcessorManager::new(
-                            self.replica_number,
-                            self.subnet_params.clone(),
-                            self.sender_outgoing_artifact.clone(),
-                            Arc::clone(&self.finalization_times),
-                        ));
-                        println!("\nArtifact manager started");
-                        self.artifact_manager_started = true;
-                    }
                 }
+                // the artifact manager starts once the handshakes with all the other replicas completed
+                self.swarm.behaviour_mut().handshake.send_request(
+                    &remote_peer_id,
+                    Handshake::new(self.subnet_params.wire_encoding),
+                );
             }
             SwarmEvent::ConnectionClosed { peer_id, .. } => {
                 println!("Peer: {} disconnected", peer_id)
@@ -303,15 +1042,48 @@ impl Peer {
         }
     }
 
-    pub fn handle_incoming_message(&mut self, message_variant: Message) {
+    /// Pass the artifacts of a message received from the given peer to the
+    /// artifact manager, attributed to the replica of the peer.
+    pub fn handle_incoming_message(
+        &mut self,
+        message_variant: Message,
+        source: PeerId,
+        authenticated: bool,
+    ) {
+        let sender = match self.subnet_peers.get(&source) {
+            Some(_) if !authenticated => UNKNOWN_SENDER,
+            Some(sender) => *sender,
+            None => {
+                println!(
+                    "Dropped message from peer: {} which is not a replica of the subnet",
+                    source
+                );
+                return;
+            }
+        };
         match message_variant {
             Message::KeepAliveMessage => (),
+            Message::ConsensusMessageBatch(consensus_messages) => {
+                for consensus_message in consensus_messages {
+                    self.handle_incoming_message(
+                        Message::ConsensusMessage(consensus_message),
+                        source,
+                        authenticated,
+                    );
+                }
+            }
             Message::ConsensusMessage(consensus_message) => {
                 // println!("\nReceived message: {:?}", consensus_message);
+                // the proposal is only validated once its payload has been downloaded
+                if let ConsensusMessage::BlockProposal(proposal) = &consensus_message {
+                    let block = &proposal.content.value;
+                    self.download_payload(block.payload_hash.clone(), block.height, source);
+                }
                 match &self.manager {
                     Some(manager) => {
                         manager.on_artifact(UnvalidatedArtifact::new(
                             consensus_message,
+                            sender,
                             system_time_now(),
                         ));
                     }
@@ -321,7 +1093,256 @@ impl Peer {
         }
     }
 
+    /// Start downloading the payload with the given hash, unless it is already
+    /// in the store or being downloaded.
+    fn download_payload(&mut self, payload_hash: CryptoHash, height: Height, source: PeerId) {
+        if self.payloads.read().unwrap().contains(&payload_hash)
+            || self.payload_downloads.contains_key(&payload_hash)
+        {
+            return;
+        }
+        // the peer which sent the proposal is asked first, as it should have the payload
+        let mut peers = vec![source];
+        peers.extend(
+            self.subscribed_peers
+                .iter()
+                .filter(|peer_id| **peer_id != source),
+        );
+        self.payload_downloads.insert(
+            payload_hash.clone(),
+            PayloadDownload {
+                height,
+                peers,
+                peer_index: 0,
+                chunks: vec![],
+            },
+        );
+        self.request_chunks(&payload_hash, vec![0]);
+    }
+
+    fn request_chunks(&mut self, payload_hash: &CryptoHash, indexes: Vec<usize>) {
+        let peer_id = match self
+            .payload_downloads
+            .get(payload_hash)
+            .and_then(|download| download.peer())
+        {
+            Some(peer_id) => peer_id,
+            // the validator requests the proposal again while its payload is missing,
+            // which restarts the download from the peers that answer
+            None => {
+                println!("Failed to download payload: {} from any peer", payload_hash);
+                self.payload_downloads.remove(payload_hash);
+                return;
+            }
+        };
+        for index in indexes {
+            let request_id = self.swarm.behaviour_mut().payload_exchange.send_request(
+                &peer_id,
+                PayloadChunkRequest {
+                    payload_hash: payload_hash.clone(),
+                    index,
+                },
+            );
+            self.chunk_requests
+                .insert(request_id, (payload_hash.clone(), peer_id));
+        }
+    }
+
+    /// Restart the download of the payload from the next peer.
+    fn retry_download(&mut self, payload_hash: &CryptoHash) {
+        if let Some(download) = self.payload_downloads.get_mut(payload_hash) {
+            download.peer_index += 1;
+            download.chunks.clear();
+            self.request_chunks(payload_hash, vec![0]);
+        }
+    }
+
+    /// Store the chunk received in response to the request with the given ID,
+    /// and the payload once all its chunks are received and it matches the
+    /// hash in the proposal. A missing chunk restarts the download from the
+    /// next peer.
+    fn on_payload_chunk(&mut self, request_id: RequestId, chunk: Option<PayloadChunk>) {
+        let (payload_hash, peer_id) = match self.chunk_requests.remove(&request_id) {
+            Some(request) => request,
+            None => return,
+        };
+        let download = match self.payload_downloads.get_mut(&payload_hash) {
+            // responses from the peers from which the download was abandoned are ignored
+            Some(download) if download.peer() == Some(peer_id) => download,
+            _ => return,
+        };
+        // the number of chunks is bounded by the size of the blocks of the subnet, so that a peer cannot
+        // make the replica allocate or request arbitrarily many chunks
+        let max_chunk_count = max_chunk_count(self.subnet_params.blocksize);
+        let chunk = match chunk {
+            Some(chunk)
+                if chunk.index < chunk.count
+                    && chunk.count <= max_chunk_count
+                    && chunk.data.len() <= PAYLOAD_CHUNK_SIZE
+                    && (download.chunks.is_empty() || download.chunks.len() == chunk.count) =>
+            {
+                chunk
+            }
+            _ => {
+                self.retry_download(&payload_hash);
+                return;
+            }
+        };
+        if download.chunks.is_empty() {
+            download.chunks = vec![None; chunk.count];
+            let remaining = (1..chunk.count).collect();
+            download.chunks[chunk.index] = Some(chunk.data);
+            self.request_chunks(&payload_hash, remaining);
+        } else {
+            download.chunks[chunk.index] = Some(chunk.data);
+        }
+        let download = &self.payload_downloads[&payload_hash];
+        if download.chunks.iter().any(|chunk| chunk.is_none()) {
+            return;
+        }
+        let bytes: Vec<u8> = download
+            .chunks
+            .iter()
+            .flatten()
+            .flatten()
+            .copied()
+            .collect();
+        if self.crypto.hash(&bytes) != payload_hash {
+            println!(
+                "Payload from peer: {} does not match hash: {}",
+                peer_id, payload_hash
+            );
+            self.retry_download(&payload_hash);
+            return;
+        }
+        let height = download.height;
+        self.payload_downloads.remove(&payload_hash);
+        // println!("\nDownloaded payload: {} of {} bytes", payload_hash, bytes.len());
+        self.payloads
+            .write()
+            .unwrap()
+            .insert(payload_hash, height, Payload::from_bytes(bytes));
+    }
+
+    /// Start processing consensus artifacts. A restarted replica starts right
+    /// away, as the other replicas are already running.
+    pub fn start_artifact_manager(&mut self) {
+        if self.manager.is_some() {
+            return;
+        }
+        self.manager = Some(ArtifactProcessorManager::new(
+            self.replica_number,
+            self.subnet_params.clone(),
+            Arc::clone(&self.crypto),
+            self.sender_outgoing_artifact.clone(),
+            Arc::clone(&self.finalization_times),
+            Arc::clone(&self.invalid_artifacts),
+            Arc::clone(&self.equivocation_evidence),
+            self.sender_artifact_request.clone(),
+            Arc::clone(&self.payloads),
+        ));
+        println!("\nArtifact manager started");
+        self.artifact_manager_started = true;
+    }
+
     pub fn artifact_manager_started(&self) -> bool {
         self.artifact_manager_started
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        consensus_layer::consensus_subcomponents::random_beacon_maker::{
+            genesis_random_beacon, RandomBeaconContent, RandomBeaconShare,
+        },
+        crypto::{CryptoHashOf, ThresholdSignatureShare},
+    };
+
+    const ENCODINGS: [WireEncoding; 2] = [WireEncoding::Binary, WireEncoding::Json];
+
+    fn consensus_messages() -> Vec<ConsensusMessage> {
+        vec![
+            ConsensusMessage::RandomBeacon(genesis_random_beacon()),
+            ConsensusMessage::RandomBeaconShare(RandomBeaconShare {
+                content: RandomBeaconContent::new(1, CryptoHashOf::from(String::from("parent"))),
+                signature: ThresholdSignatureShare {
+                    signer: 2,
+                    signature: vec![0, 1, 255],
+                },
+            }),
+        ]
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        for encoding in ENCODINGS {
+            let bytes = encoding.encode(&Message::ConsensusMessageBatch(consensus_messages()));
+            assert_eq!(bytes[0], WIRE_PROTOCOL_VERSION);
+            match encoding.decode::<Message>(&bytes) {
+                Ok(Message::ConsensusMessageBatch(messages)) => {
+                    assert_eq!(messages, consensus_messages())
+                }
+                other => panic!("unexpected message {:?}", other),
+            }
+            let bytes = encoding.encode(&Message::KeepAliveMessage);
+            assert!(matches!(
+                encoding.decode::<Message>(&bytes),
+                Ok(Message::KeepAliveMessage)
+            ));
+        }
+    }
+
+    #[test]
+    fn payload_chunks_round_trip() {
+        for encoding in ENCODINGS {
+            let chunk = PayloadChunk {
+                index: 1,
+                count: 3,
+                data: vec![7; 100],
+            };
+            let decoded: PayloadChunk = encoding.decode(&encoding.encode(&chunk)).unwrap();
+            assert_eq!(
+                (decoded.index, decoded.count, decoded.data),
+                (chunk.index, chunk.count, chunk.data)
+            );
+        }
+    }
+
+    #[test]
+    fn other_wire_protocol_versions_are_refused() {
+        for encoding in ENCODINGS {
+            let mut bytes = encoding.encode(&Message::KeepAliveMessage);
+            for version in [0, WIRE_PROTOCOL_VERSION - 1, WIRE_PROTOCOL_VERSION + 1] {
+                bytes[0] = version;
+                assert_eq!(
+                    encoding.decode::<Message>(&bytes).unwrap_err(),
+                    format!("unsupported wire protocol version {}", version)
+                );
+            }
+            assert!(encoding.decode::<Message>(&[]).is_err());
+            assert!(encoding
+                .decode::<Message>(&[WIRE_PROTOCOL_VERSION])
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn messages_of_another_encoding_are_refused() {
+        let message = Message::ConsensusMessageBatch(consensus_messages());
+        let json = WireEncoding::Json.encode(&message);
+        assert!(WireEncoding::Binary.decode::<Message>(&json).is_err());
+        let binary = WireEncoding::Binary.encode(&message);
+        assert!(WireEncoding::Json.decode::<Message>(&binary).is_err());
+    }
+
+    #[test]
+    fn handshake_records_the_version_and_encoding() {
+        let binary = Handshake::new(WireEncoding::Binary);
+        let json = Handshake::new(WireEncoding::Json);
+        assert_eq!(binary.version, WIRE_PROTOCOL_VERSION);
+        assert_eq!(json.version, WIRE_PROTOCOL_VERSION);
+        assert_ne!(binary, json);
+    }
+}
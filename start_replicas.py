@@ -99,6 +99,23 @@ if UPDATE_REPO:
     print("\nContainer built on replicas")
 
 
+# The keys of the replicas are generated once for the subnet and each instance only receives its own keys,
+# which the container reads from the mounted ./keys folder
+print("\nGenerating keys of the replicas")
+
+subnet_keys_dir = "./keys/subnet"
+generate_keys_cmd = f"cargo run --release -- --generate_subnet_keys --r 1 --n {N} --f {F} --key_dir {subnet_keys_dir}"
+subprocess.run(generate_keys_cmd, shell=True, check=True)
+
+for peer in peers:
+    print("\nCopying keys to replica", peer["number"])
+    os.chmod("./keys/" + peer["key_file"], 0o400)
+    copy_keys_cmd = f'scp -i ./keys/{peer["key_file"]} {subnet_keys_dir}/membership.json {subnet_keys_dir}/replica_{peer["number"]}_*.key ubuntu@{peer["ip"]}:fast_internet_computer_consensus/keys/'
+    subprocess.run(copy_keys_cmd, shell=True, check=True)
+
+print("\nKeys copied to replicas")
+
+
 print(
     "\nStarting subnet running "
     + ("FICC" if FICC else "ICC")